@@ -0,0 +1,29 @@
+// Shows the accumulated radiance on the target. Runs after every compute
+// pass, so it stays cheap: one texel load per pixel.
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+}
+
+@group(0) @binding(0) var accumulated: texture_2d<f32>;
+
+// A single triangle covering the whole target, no vertex buffer needed
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureLoad(accumulated, vec2<u32>(in.clip_position.xy), 0);
+    return vec4<f32>(tonemap(color.rgb), 1.0);
+}
+
+// Linear radiance clipped to the displayable range, the sRGB target encodes
+// it on write
+fn tonemap(color: vec3<f32>) -> vec3<f32> {
+    return clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+}
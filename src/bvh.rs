@@ -0,0 +1,563 @@
+use nalgebra::{Matrix4, Vector3};
+use crate::hitable::*;
+
+const BIN_COUNT: usize = 16;
+// Keeps the shader's traversal stack (64 entries) from overflowing
+const MAX_DEPTH: u32 = 48;
+pub const STACK_SIZE: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        Self {
+            min,
+            max,
+        }
+    }
+
+    pub fn empty() -> Self {
+        Self::new(Vector3::repeat(f32::MAX), Vector3::repeat(-f32::MAX))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&mut self, p: &Vector3<f32>) {
+        self.min = self.min.inf(p);
+        self.max = self.max.sup(p);
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(self.min.inf(&other.min), self.max.sup(&other.max))
+    }
+
+    pub fn contains(&self, other: &Aabb) -> bool {
+        self.min.iter().zip(other.min.iter()).all(|(a, b)| a <= b)
+            && self.max.iter().zip(other.max.iter()).all(|(a, b)| a >= b)
+    }
+
+    /// Bounds of the box after an affine transform
+    pub fn transform(&self, m: &Matrix4<f32>) -> Aabb {
+        let mut bounds = Aabb::empty();
+        for corner in 0..8 {
+            let p = Vector3::new(
+                if corner & 1 == 0 { self.min.x } else { self.max.x },
+                if corner & 2 == 0 { self.min.y } else { self.max.y },
+                if corner & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            bounds.grow(&m.transform_point(&p.into()).coords);
+        }
+        bounds
+    }
+
+    pub fn centroid(&self) -> Vector3<f32> {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Slab test, returns the entry distance when the ray overlaps [t_min, t_max].
+    /// Mirrors `hit_aabb` in the shader.
+    pub fn hit(&self, ray: &Ray, inv_direction: &Vector3<f32>, t_min: f32, t_max: f32) -> Option<f32> {
+        let t0 = (self.min - ray.origin).component_mul(inv_direction);
+        let t1 = (self.max - ray.origin).component_mul(inv_direction);
+        let t_near = t0.inf(&t1).max().max(t_min);
+        let t_far = t0.sup(&t1).min().min(t_max);
+        if t_near <= t_far {
+            Some(t_near)
+        } else {
+            None
+        }
+    }
+}
+
+/// A flattened BVH node as laid out in the shader's `bvh_nodes` buffer.
+/// Interior nodes have `count == 0` and their children stored at
+/// `left_first` and `left_first + 1`; leaves cover the hitables
+/// `left_first..left_first + count`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct BvhNode {
+    pub min: Vector3<f32>,
+    pub left_first: u32,
+    pub max: Vector3<f32>,
+    pub count: u32,
+}
+
+impl BvhNode {
+    pub fn bounds(&self) -> Aabb {
+        Aabb::new(self.min, self.max)
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+unsafe impl bytemuck::Pod for BvhNode {}
+unsafe impl bytemuck::Zeroable for BvhNode {}
+
+#[derive(Clone, Debug)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Bin {
+    bounds: Option<Aabb>,
+    count: u32,
+}
+
+struct Builder {
+    nodes: Vec<BvhNode>,
+    bounds: Vec<Aabb>,
+    centroids: Vec<Vector3<f32>>,
+    order: Vec<usize>,
+}
+
+impl Bvh {
+    /// Builds a binned SAH tree over the hitables, reordering them so every leaf
+    /// references a contiguous range. Lists must not be empty.
+    pub fn build(hitable_list: &mut HitableList) -> Self {
+        let bounds = (0..hitable_list.hitables.len()).map(|i| hitable_list.bounds(i)).collect();
+        let (bvh, order) = Self::from_bounds(bounds);
+        hitable_list.hitables = order
+            .iter()
+            .map(|&i| hitable_list.hitables[i])
+            .collect();
+        bvh
+    }
+
+    /// Builds a tree over arbitrary primitives. Leaf ranges index into the
+    /// returned order, which maps back to positions in `bounds`.
+    pub fn from_bounds(bounds: Vec<Aabb>) -> (Self, Vec<usize>) {
+        let count = bounds.len();
+        assert!(count > 0, "can't build a BVH without primitives");
+        let mut builder = Builder {
+            nodes: Vec::with_capacity(2 * count),
+            centroids: bounds.iter().map(|b| b.centroid()).collect(),
+            bounds,
+            order: (0..count).collect(),
+        };
+        builder.nodes.push(BvhNode {
+            min: Vector3::zeros(),
+            left_first: 0,
+            max: Vector3::zeros(),
+            count: count as u32,
+        });
+        builder.subdivide(0, 0);
+        (Self { nodes: builder.nodes }, builder.order)
+    }
+
+    /// Returns the index of the closest hitable and the hit distance.
+    pub fn hit(&self, hitable_list: &HitableList, ray: &Ray, t_min: f32, t_max: f32) -> Option<(usize, f32)> {
+        traverse(&self.nodes, 0, ray, t_min, t_max, |i, t_max| hitable_list.hit(i as usize, ray, t_min, t_max))
+            .map(|(i, t)| (i as usize, t))
+    }
+}
+
+/// Stack traversal mirroring the shader. `hit_primitive` is called with a
+/// primitive index and the current closest distance and returns a closer hit.
+pub fn traverse<F>(nodes: &[BvhNode], root: u32, ray: &Ray, t_min: f32, t_max: f32, mut hit_primitive: F) -> Option<(u32, f32)>
+where
+    F: FnMut(u32, f32) -> Option<f32>,
+{
+    let inv_direction = ray.direction.map(|d| 1.0 / d);
+    let mut closest_so_far = t_max;
+    let mut closest = None;
+    nodes[root as usize].bounds().hit(ray, &inv_direction, t_min, closest_so_far)?;
+    let mut stack = Vec::with_capacity(STACK_SIZE);
+    stack.push(root as usize);
+    while let Some(index) = stack.pop() {
+        let node = &nodes[index];
+        if node.is_leaf() {
+            for i in node.left_first..node.left_first + node.count {
+                if let Some(t) = hit_primitive(i, closest_so_far) {
+                    closest_so_far = t;
+                    closest = Some((i, t));
+                }
+            }
+            continue;
+        }
+        let left = node.left_first as usize;
+        let right = left + 1;
+        let left_t = nodes[left].bounds().hit(ray, &inv_direction, t_min, closest_so_far);
+        let right_t = nodes[right].bounds().hit(ray, &inv_direction, t_min, closest_so_far);
+        // Push the farther child first so the nearer one is visited next
+        match (left_t, right_t) {
+            (Some(l), Some(r)) if l < r => stack.extend([right, left]),
+            (Some(_), Some(_)) => stack.extend([left, right]),
+            (Some(_), None) => stack.push(left),
+            (None, Some(_)) => stack.push(right),
+            (None, None) => {}
+        }
+    }
+    closest
+}
+
+/// Shifts child links of interior nodes and primitive ranges of leaves, used
+/// when concatenating trees into one buffer.
+pub fn offset_nodes(nodes: &mut [BvhNode], child_offset: u32, primitive_offset: u32) {
+    for node in nodes {
+        if node.is_leaf() {
+            node.left_first += primitive_offset;
+        } else {
+            node.left_first += child_offset;
+        }
+    }
+}
+
+/// The GPU side of the two level acceleration structure: the top level tree
+/// over the hitables followed by every mesh's bottom level tree, and the
+/// instances pointing into it.
+#[derive(Clone, Debug)]
+pub struct SceneBvh {
+    pub nodes: Vec<BvhNode>,
+    pub instances: Vec<Instance>,
+}
+
+impl SceneBvh {
+    pub fn build(hitable_list: &mut HitableList) -> Self {
+        let mut nodes = Bvh::build(hitable_list).nodes;
+        let blas_offset = nodes.len() as u32;
+        let mut blas_nodes = hitable_list.blas_nodes.clone();
+        offset_nodes(&mut blas_nodes, blas_offset, 0);
+        nodes.extend(blas_nodes);
+        let instances = hitable_list.instances
+            .iter()
+            .map(|instance| {
+                let mut instance = *instance;
+                instance.blas_root += blas_offset;
+                instance
+            })
+            .collect();
+        Self {
+            nodes,
+            instances,
+        }
+    }
+}
+
+impl Builder {
+    fn subdivide(&mut self, node_index: usize, depth: u32) {
+        let first = self.nodes[node_index].left_first as usize;
+        let count = self.nodes[node_index].count as usize;
+        let prims = first..first + count;
+
+        let mut bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for &i in &self.order[prims.clone()] {
+            bounds = bounds.union(&self.bounds[i]);
+            centroid_bounds.grow(&self.centroids[i]);
+        }
+        self.nodes[node_index].min = bounds.min;
+        self.nodes[node_index].max = bounds.max;
+        if count <= 1 || depth >= MAX_DEPTH {
+            return;
+        }
+
+        let Some((axis, split)) = self.find_split(&self.order[prims.clone()], &bounds, &centroid_bounds) else {
+            return;
+        };
+        let bin_of = |c: &Vector3<f32>| bin_index(c[axis], centroid_bounds.min[axis], centroid_bounds.max[axis]);
+        let centroids = &self.centroids;
+        let mut left_count = 0;
+        for i in prims.clone() {
+            if bin_of(&centroids[self.order[i]]) < split {
+                self.order.swap(first + left_count, i);
+                left_count += 1;
+            }
+        }
+        if left_count == 0 || left_count == count {
+            return;
+        }
+
+        let left = self.nodes.len();
+        self.nodes[node_index].left_first = left as u32;
+        self.nodes[node_index].count = 0;
+        for (first, count) in [(first, left_count), (first + left_count, count - left_count)] {
+            self.nodes.push(BvhNode {
+                min: Vector3::zeros(),
+                left_first: first as u32,
+                max: Vector3::zeros(),
+                count: count as u32,
+            });
+        }
+        self.subdivide(left, depth + 1);
+        self.subdivide(left + 1, depth + 1);
+    }
+
+    /// Returns the axis and bin boundary with the lowest SAH cost, or None when
+    /// keeping the node as a leaf is cheaper.
+    fn find_split(&self, prims: &[usize], bounds: &Aabb, centroid_bounds: &Aabb) -> Option<(usize, usize)> {
+        let leaf_cost = prims.len() as f32;
+        let parent_area = bounds.surface_area().max(f32::MIN_POSITIVE);
+        let mut best: Option<(usize, usize)> = None;
+        let mut best_cost = leaf_cost;
+        for axis in 0..3 {
+            let (lo, hi) = (centroid_bounds.min[axis], centroid_bounds.max[axis]);
+            if hi <= lo {
+                continue;
+            }
+            let mut bins = [Bin::default(); BIN_COUNT];
+            for &i in prims {
+                let bin = &mut bins[bin_index(self.centroids[i][axis], lo, hi)];
+                bin.count += 1;
+                bin.bounds = Some(bin.bounds.map_or(self.bounds[i], |b| b.union(&self.bounds[i])));
+            }
+            // Sweep from the right to get the cost of everything above each split
+            let mut right_area = [0.0; BIN_COUNT];
+            let mut right_count = [0; BIN_COUNT];
+            let mut acc = Bin::default();
+            for b in (1..BIN_COUNT).rev() {
+                acc = merge(acc, bins[b]);
+                right_area[b] = acc.bounds.map_or(0.0, |b| b.surface_area());
+                right_count[b] = acc.count;
+            }
+            let mut acc = Bin::default();
+            for split in 1..BIN_COUNT {
+                acc = merge(acc, bins[split - 1]);
+                if acc.count == 0 || right_count[split] == 0 {
+                    continue;
+                }
+                let left_area = acc.bounds.map_or(0.0, |b| b.surface_area());
+                let cost = 1.0
+                    + (acc.count as f32 * left_area + right_count[split] as f32 * right_area[split]) / parent_area;
+                if cost < best_cost {
+                    best_cost = cost;
+                    best = Some((axis, split));
+                }
+            }
+        }
+        best
+    }
+}
+
+fn merge(a: Bin, b: Bin) -> Bin {
+    let bounds = match (a.bounds, b.bounds) {
+        (Some(a), Some(b)) => Some(a.union(&b)),
+        (a, b) => a.or(b),
+    };
+    Bin {
+        bounds,
+        count: a.count + b.count,
+    }
+}
+
+fn bin_index(c: f32, lo: f32, hi: f32) -> usize {
+    let bin = ((c - lo) / (hi - lo) * BIN_COUNT as f32) as usize;
+    bin.min(BIN_COUNT - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Small deterministic generator so the tests don't need a rand dependency
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> f32 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 40) as f32 / (1u64 << 24) as f32
+        }
+
+        fn vector(&mut self, scale: f32) -> Vector3<f32> {
+            Vector3::new(self.next() - 0.5, self.next() - 0.5, self.next() - 0.5) * scale
+        }
+    }
+
+    fn material() -> Material {
+        Material::new(Vector3::new(0.5, 0.5, 0.5), LAMBERTIAN)
+    }
+
+    fn random_scene(rng: &mut Lcg, spheres: usize) -> HitableList {
+        let mut list = HitableList::new();
+        for _ in 0..spheres {
+            let sphere = Sphere::new(rng.vector(20.0), 0.1 + rng.next());
+            list.push(Hitable::new(SPHERE, sphere, material()));
+        }
+        let positions = vec![rng.vector(10.0), rng.vector(10.0), rng.vector(10.0), rng.vector(10.0)];
+        list.push_mesh(Mesh::new(positions, vec![[0, 1, 2], [0, 2, 3]], material()));
+        list
+    }
+
+    fn brute_force(list: &HitableList, ray: &Ray) -> Option<(usize, f32)> {
+        let mut closest = None;
+        let mut t_max = f32::MAX;
+        for i in 0..list.hitables.len() {
+            if let Some(t) = list.hit(i, ray, 0.001, t_max) {
+                t_max = t;
+                closest = Some((i, t));
+            }
+        }
+        closest
+    }
+
+    #[test]
+    fn node_layout_is_32_bytes() {
+        assert_eq!(std::mem::size_of::<BvhNode>(), 32);
+        assert_eq!(std::mem::offset_of!(BvhNode, left_first), 12);
+        assert_eq!(std::mem::offset_of!(BvhNode, max), 16);
+        assert_eq!(std::mem::offset_of!(BvhNode, count), 28);
+    }
+
+    #[test]
+    fn leaves_cover_every_hitable_once() {
+        let mut rng = Lcg(1);
+        let mut list = random_scene(&mut rng, 500);
+        let bvh = Bvh::build(&mut list);
+        let mut covered = vec![0; list.hitables.len()];
+        for node in &bvh.nodes {
+            if node.is_leaf() {
+                for i in node.left_first..node.left_first + node.count {
+                    covered[i as usize] += 1;
+                    assert!(node.bounds().contains(&list.bounds(i as usize)));
+                }
+            } else {
+                for child in [node.left_first, node.left_first + 1] {
+                    assert!(node.bounds().contains(&bvh.nodes[child as usize].bounds()));
+                }
+            }
+        }
+        assert!(covered.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let mut rng = Lcg(7);
+        let mut list = random_scene(&mut rng, 300);
+        let bvh = Bvh::build(&mut list);
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let ray = Ray::new(rng.vector(30.0), rng.vector(2.0));
+            let expected = brute_force(&list, &ray);
+            let actual = bvh.hit(&list, &ray, 0.001, f32::MAX);
+            assert_eq!(expected.map(|e| e.0), actual.map(|a| a.0));
+            hits += expected.is_some() as u32;
+        }
+        assert!(hits > 100, "too few rays hit anything to be a useful test");
+    }
+
+    #[test]
+    fn instances_hit_transformed_triangles() {
+        let mut rng = Lcg(3);
+        let positions: Vec<Vector3<f32>> = (0..60).map(|_| rng.vector(2.0)).collect();
+        let indices: Vec<[u32; 3]> = (0..20).map(|t| [3 * t, 3 * t + 1, 3 * t + 2]).collect();
+        let mut list = HitableList::new();
+        let mesh = list.add_mesh(Mesh::new(positions.clone(), indices.clone(), material()));
+        let transforms = [
+            Matrix4::new_translation(&Vector3::new(4.0, 0.0, 0.0)),
+            Matrix4::new_rotation(Vector3::new(0.3, 1.2, -0.4)) * Matrix4::new_scaling(1.5),
+        ];
+        for transform in transforms {
+            list.push_instance(mesh, transform, None);
+        }
+        let bvh = SceneBvh::build(&mut list);
+        assert_eq!(bvh.instances.len(), 2);
+
+        // Brute force against world space copies of every triangle
+        let mut world = Vec::new();
+        for m in &transforms {
+            for triangle in &indices {
+                world.push(triangle.map(|i| m.transform_point(&positions[i as usize].into()).coords));
+            }
+        }
+        let mut hits = 0;
+        for _ in 0..1000 {
+            // Aim roughly at the instances so enough rays hit
+            let origin = rng.vector(20.0);
+            let target = rng.vector(4.0) + Vector3::new(2.0, 0.0, 0.0);
+            let ray = Ray::new(origin, target - origin);
+            let expected = world
+                .iter()
+                .filter_map(|[p0, p1, p2]| intersect_triangle(&ray, *p0, *p1, *p2))
+                .map(|(t, _, _)| t)
+                .filter(|&t| t > 0.001)
+                .fold(None, |closest: Option<f32>, t| Some(closest.map_or(t, |c| c.min(t))));
+            let actual = (0..list.hitables.len())
+                .filter_map(|i| list.hit(i, &ray, 0.001, f32::MAX))
+                .fold(None, |closest: Option<f32>, t| Some(closest.map_or(t, |c| c.min(t))));
+            match (expected, actual) {
+                (Some(e), Some(a)) => assert!((e - a).abs() < 1e-3 * e.max(1.0), "{} != {}", e, a),
+                (None, None) => {}
+                _ => panic!("hit mismatch: {:?} vs {:?}", expected, actual),
+            }
+            hits += expected.is_some() as u32;
+        }
+        assert!(hits > 100, "too few rays hit anything to be a useful test");
+    }
+
+    #[test]
+    fn appended_lists_hit_where_they_were_moved() {
+        let mut rng = Lcg(11);
+        let other = random_scene(&mut rng, 20);
+        let mut list = HitableList::new();
+        list.push_mesh(Mesh::new(vec![rng.vector(5.0), rng.vector(5.0), rng.vector(5.0)], vec![[0, 1, 2]], material()));
+        let transform = Matrix4::new_translation(&Vector3::new(0.0, 3.0, -2.0))
+            * Matrix4::new_rotation(Vector3::new(0.0, 0.7, 0.0))
+            * Matrix4::new_scaling(0.5);
+        list.append(other.clone(), transform, None);
+        assert_eq!(list.hitables.len(), other.hitables.len() + 1);
+
+        // Rays moved into the appended list's own space hit at the same t
+        let inverse = transform.try_inverse().unwrap();
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let origin = rng.vector(20.0);
+            let target = transform.transform_point(&rng.vector(15.0).into()).coords;
+            let ray = Ray::new(origin, target - origin);
+            let local = Ray::new(
+                inverse.transform_point(&ray.origin.into()).coords,
+                inverse.transform_vector(&ray.direction),
+            );
+            let closest = |list: &HitableList, range: std::ops::Range<usize>, ray: &Ray| {
+                range
+                    .filter_map(|i| list.hit(i, ray, 0.001, f32::MAX))
+                    .fold(None, |closest: Option<f32>, t| Some(closest.map_or(t, |c| c.min(t))))
+            };
+            let expected = closest(&other, 0..other.hitables.len(), &local);
+            let actual = closest(&list, 1..list.hitables.len(), &ray);
+            match (expected, actual) {
+                (Some(e), Some(a)) => assert!((e - a).abs() < 1e-3 * e.max(1.0), "{} != {}", e, a),
+                (None, None) => {}
+                _ => panic!("hit mismatch: {:?} vs {:?}", expected, actual),
+            }
+            hits += expected.is_some() as u32;
+        }
+        assert!(hits > 50, "too few rays hit anything to be a useful test");
+
+        // Stretched spheres stay round and keep their volume
+        let mut spheres = HitableList::new();
+        spheres.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(1.0, 1.0, 0.0), 0.5), material()));
+        let mut list = HitableList::new();
+        list.append(spheres, Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, 8.0, 1.0)), None);
+        assert_eq!(list.hitables[0].sphere.center, Vector3::new(1.0, 8.0, 0.0));
+        assert!((list.hitables[0].sphere.radius - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn single_hitable_is_a_leaf_root() {
+        let mut list = HitableList::new();
+        list.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(0.0, 0.0, -1.0), 0.5), material()));
+        let bvh = Bvh::build(&mut list);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert!(bvh.nodes[0].is_leaf());
+        let ray = Ray::new(Vector3::zeros(), Vector3::new(0.0, 0.0, -1.0));
+        let (index, t) = bvh.hit(&list, &ray, 0.001, f32::MAX).unwrap();
+        assert_eq!(index, 0);
+        assert!((t - 0.5).abs() < 1e-5);
+    }
+}
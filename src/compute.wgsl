@@ -0,0 +1,66 @@
+// The compute integrator: scene data in storage buffers, accumulation
+// ping-ponged between two textures and one invocation per pixel. Appended
+// to shader.wgsl.
+
+struct LightList {
+    count: u32,
+    lights: array<Light>,
+}
+
+@group(1) @binding(0) var<storage,read> hitabble_list: array<Hitable>;
+@group(1) @binding(1) var<storage,read> mesh_vertices: array<MeshVertex>;
+@group(1) @binding(2) var<storage,read> mesh_indices: array<u32>;
+@group(1) @binding(3) var<storage,read> texels: array<u32>;
+@group(1) @binding(4) var<storage,read> light_list: LightList;
+
+// The last average is read from one texture and the new one written to the other
+@group(2) @binding(0) var previous_frame: texture_2d<f32>;
+@group(2) @binding(1) var next_frame: texture_storage_2d<rgba32float, write>;
+
+@group(3) @binding(0) var<storage,read> bvh_nodes: array<BvhNode>;
+@group(3) @binding(1) var<storage,read> instances: array<Instance>;
+
+// One invocation per pixel, the dispatch is rounded up to whole workgroups
+@compute @workgroup_size(8, 8)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= camera.image_width || id.y >= u32(camera.image_height)) {
+        return;
+    }
+    var prev_color = vec4<f32>(0.0);
+    if (camera.iteration > 1u) {
+        prev_color = textureLoad(previous_frame, id.xy, 0);
+    }
+    textureStore(next_frame, id.xy, trace_pixel(id.xy, prev_color));
+}
+
+fn hitable_at(index: u32) -> Hitable {
+    return hitabble_list[index];
+}
+
+fn mesh_vertex_at(index: u32) -> MeshVertex {
+    return mesh_vertices[index];
+}
+
+fn mesh_index_at(index: u32) -> u32 {
+    return mesh_indices[index];
+}
+
+fn texel_at(index: u32) -> u32 {
+    return texels[index];
+}
+
+fn light_count() -> u32 {
+    return light_list.count;
+}
+
+fn light_at(index: u32) -> Light {
+    return light_list.lights[index];
+}
+
+fn bvh_node_at(index: u32) -> BvhNode {
+    return bvh_nodes[index];
+}
+
+fn instance_at(index: u32) -> Instance {
+    return instances[index];
+}
@@ -0,0 +1,155 @@
+// The downlevel integrator for WebGL2 and other adapters without compute
+// shaders or fragment storage buffers. Scene data is packed into Rgba32Uint
+// data textures, laid out exactly like the storage buffers of compute.wgsl,
+// 16 bytes per texel in rows as wide as the texture. Each iteration is a
+// fullscreen pass rendering the new average into the other history texture.
+// Appended to shader.wgsl.
+
+@group(1) @binding(0) var hitable_data: texture_2d<u32>;
+@group(1) @binding(1) var mesh_vertex_data: texture_2d<u32>;
+@group(1) @binding(2) var mesh_index_data: texture_2d<u32>;
+@group(1) @binding(3) var texel_data: texture_2d<u32>;
+@group(1) @binding(4) var light_data: texture_2d<u32>; // count, then the lights from the second texel
+
+@group(2) @binding(0) var previous_frame: texture_2d<f32>;
+
+@group(3) @binding(0) var bvh_node_data: texture_2d<u32>;
+@group(3) @binding(1) var instance_data: texture_2d<u32>;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+}
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let pixel = vec2<u32>(in.clip_position.xy);
+    var prev_color = vec4<f32>(0.0);
+    if (camera.iteration > 1u) {
+        prev_color = textureLoad(previous_frame, pixel, 0);
+    }
+    return trace_pixel(pixel, prev_color);
+}
+
+// Texture handles can't be passed to functions on every backend, so each
+// data texture gets its own fetch
+fn data_coords(index: u32, width: u32) -> vec2<u32> {
+    return vec2<u32>(index % width, index / width);
+}
+
+fn hitable_texel(index: u32) -> vec4<u32> {
+    return textureLoad(hitable_data, data_coords(index, textureDimensions(hitable_data).x), 0);
+}
+
+fn mesh_vertex_texel(index: u32) -> vec4<u32> {
+    return textureLoad(mesh_vertex_data, data_coords(index, textureDimensions(mesh_vertex_data).x), 0);
+}
+
+fn mesh_index_texel(index: u32) -> vec4<u32> {
+    return textureLoad(mesh_index_data, data_coords(index, textureDimensions(mesh_index_data).x), 0);
+}
+
+fn texel_texel(index: u32) -> vec4<u32> {
+    return textureLoad(texel_data, data_coords(index, textureDimensions(texel_data).x), 0);
+}
+
+fn light_texel(index: u32) -> vec4<u32> {
+    return textureLoad(light_data, data_coords(index, textureDimensions(light_data).x), 0);
+}
+
+fn bvh_node_texel(index: u32) -> vec4<u32> {
+    return textureLoad(bvh_node_data, data_coords(index, textureDimensions(bvh_node_data).x), 0);
+}
+
+fn instance_texel(index: u32) -> vec4<u32> {
+    return textureLoad(instance_data, data_coords(index, textureDimensions(instance_data).x), 0);
+}
+
+fn as_vec3(words: vec4<u32>) -> vec3<f32> {
+    return bitcast<vec3<f32>>(words.xyz);
+}
+
+fn as_mat4(first: u32) -> mat4x4<f32> {
+    return mat4x4<f32>(
+        bitcast<vec4<f32>>(instance_texel(first)),
+        bitcast<vec4<f32>>(instance_texel(first + 1u)),
+        bitcast<vec4<f32>>(instance_texel(first + 2u)),
+        bitcast<vec4<f32>>(instance_texel(first + 3u)),
+    );
+}
+
+// 80 bytes: kind, instance, padding, sphere, then the 48 byte material
+fn hitable_at(index: u32) -> Hitable {
+    let header = hitable_texel(index * 5u);
+    let sphere = hitable_texel(index * 5u + 1u);
+    let albedo = hitable_texel(index * 5u + 2u);
+    let texture = hitable_texel(index * 5u + 3u);
+    let emission = hitable_texel(index * 5u + 4u);
+    let material = Material(
+        as_vec3(albedo),
+        albedo.w,
+        Texture(texture.x, texture.y, texture.z),
+        bitcast<f32>(texture.w),
+        as_vec3(emission),
+        bitcast<f32>(emission.w),
+    );
+    return Hitable(header.x, header.y, Sphere(as_vec3(sphere), bitcast<f32>(sphere.w)), material);
+}
+
+// 32 bytes: position and u, normal and v
+fn mesh_vertex_at(index: u32) -> MeshVertex {
+    let position = mesh_vertex_texel(index * 2u);
+    let normal = mesh_vertex_texel(index * 2u + 1u);
+    return MeshVertex(as_vec3(position), bitcast<f32>(position.w), as_vec3(normal), bitcast<f32>(normal.w));
+}
+
+fn mesh_index_at(index: u32) -> u32 {
+    return mesh_index_texel(index / 4u)[index % 4u];
+}
+
+fn texel_at(index: u32) -> u32 {
+    return texel_texel(index / 4u)[index % 4u];
+}
+
+fn light_count() -> u32 {
+    return light_texel(0u).x;
+}
+
+// 64 bytes after the 16 byte count
+fn light_at(index: u32) -> Light {
+    let first = 1u + index * 4u;
+    let p0 = light_texel(first);
+    let p1 = light_texel(first + 1u);
+    let p2 = light_texel(first + 2u);
+    let emission = light_texel(first + 3u);
+    return Light(
+        as_vec3(p0),
+        p0.w,
+        as_vec3(p1),
+        bitcast<f32>(p1.w),
+        as_vec3(p2),
+        bitcast<f32>(p2.w),
+        as_vec3(emission),
+    );
+}
+
+// 32 bytes: min and left_first, max and count
+fn bvh_node_at(index: u32) -> BvhNode {
+    let min = bvh_node_texel(index * 2u);
+    let max = bvh_node_texel(index * 2u + 1u);
+    return BvhNode(as_vec3(min), min.w, as_vec3(max), max.w);
+}
+
+// 144 bytes: both matrices then the BLAS root
+fn instance_at(index: u32) -> Instance {
+    let first = index * 9u;
+    return Instance(as_mat4(first), as_mat4(first + 4u), instance_texel(first + 8u).x);
+}
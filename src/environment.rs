@@ -0,0 +1,232 @@
+use std::path::Path;
+
+/// Equirectangular HDR image lighting the scene from infinitely far away.
+/// Rows go from straight up (+y) to straight down, and the center column
+/// looks down -z before rotation.
+#[derive(Clone, Debug)]
+pub struct Environment {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 4]>, // linear RGB radiance, rows top to bottom
+    pub rotation: f32, // degrees about +y
+    pub intensity: f32,
+}
+
+impl Environment {
+    pub fn new(width: u32, height: u32, pixels: Vec<[f32; 4]>) -> Self {
+        assert_eq!(pixels.len(), width as usize * height as usize, "environment size mismatch");
+        Self {
+            width,
+            height,
+            pixels,
+            rotation: 0.0,
+            intensity: 1.0,
+        }
+    }
+
+    /// Loads a Radiance `.hdr` or OpenEXR file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.into_rgba32f();
+        let (width, height) = image.dimensions();
+        let pixels = image.pixels().map(|pixel| pixel.0).collect();
+        Ok(Self::new(width, height, pixels))
+    }
+
+    pub fn with_rotation(mut self, degrees: f32) -> Self {
+        self.rotation = degrees;
+        self
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    /// Halves the resolution with a box filter until both sides fit
+    pub fn fit(mut self, max_dimension: u32) -> Self {
+        while self.width > max_dimension || self.height > max_dimension {
+            let width = (self.width / 2).max(1);
+            let height = (self.height / 2).max(1);
+            let mut pixels = Vec::with_capacity(width as usize * height as usize);
+            for y in 0..height {
+                for x in 0..width {
+                    let mut sum = [0.0; 4];
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let sx = (2 * x + dx).min(self.width - 1);
+                        let sy = (2 * y + dy).min(self.height - 1);
+                        let pixel = self.pixels[(sy * self.width + sx) as usize];
+                        for (sum, value) in sum.iter_mut().zip(pixel) {
+                            *sum += value / 4.0;
+                        }
+                    }
+                    pixels.push(sum);
+                }
+            }
+            self.width = width;
+            self.height = height;
+            self.pixels = pixels;
+        }
+        self
+    }
+
+    /// Distribution for importance sampling the map, see `EnvironmentDistribution`
+    pub fn distribution(&self) -> EnvironmentDistribution {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let texel: Vec<f32> = self.pixels
+            .iter()
+            .map(|p| (0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]).max(0.0))
+            .collect();
+        // Bilinear filtering bleeds each texel into its neighbours, so use the
+        // brightest texel a lookup inside the pixel can reach. Otherwise the
+        // surroundings of a small sun are found by BSDF samples alone.
+        let luminance: Vec<f32> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let mut brightest: f32 = 0.0;
+                for sy in y.saturating_sub(1)..(y + 2).min(height) {
+                    for dx in [width - 1, 0, 1] {
+                        brightest = brightest.max(texel[sy * width + (x + dx) % width]);
+                    }
+                }
+                brightest
+            })
+            .collect();
+        // A small floor keeps every pixel reachable
+        let mean = luminance.iter().sum::<f32>() / luminance.len() as f32;
+        let floor = if mean > 0.0 { mean * 1e-3 } else { 1.0 };
+
+        let mut conditional = vec![0.0; width * height];
+        let mut marginal = vec![0.0; height];
+        let mut total = 0.0;
+        for y in 0..height {
+            // Rows near the poles cover less solid angle
+            let sin_theta = (std::f32::consts::PI * (y as f32 + 0.5) / height as f32).sin();
+            let row = &mut conditional[y * width..(y + 1) * width];
+            let mut sum = 0.0;
+            for (x, cdf) in row.iter_mut().enumerate() {
+                sum += (luminance[y * width + x] + floor) * sin_theta;
+                *cdf = sum;
+            }
+            for cdf in row.iter_mut() {
+                *cdf /= sum;
+            }
+            row[width - 1] = 1.0;
+            total += sum;
+            marginal[y] = total;
+        }
+        for cdf in marginal.iter_mut() {
+            *cdf /= total;
+        }
+        marginal[height - 1] = 1.0;
+
+        EnvironmentDistribution {
+            width: self.width,
+            height: self.height,
+            marginal,
+            conditional,
+        }
+    }
+
+    pub fn settings(&self) -> EnvironmentSettings {
+        EnvironmentSettings {
+            rotation: self.rotation.to_radians(),
+            intensity: self.intensity,
+            enabled: 1,
+            _padding: 0,
+        }
+    }
+}
+
+/// Piecewise constant distribution over the map's pixels, proportional to
+/// luminance times the solid angle each pixel covers. Both tables are CDFs
+/// that end at 1, uploaded as textures for `sample_environment_direction`.
+#[derive(Clone, Debug)]
+pub struct EnvironmentDistribution {
+    pub width: u32,
+    pub height: u32,
+    pub marginal: Vec<f32>,    // over rows
+    pub conditional: Vec<f32>, // over the pixels of each row, rows top to bottom
+}
+
+impl EnvironmentDistribution {
+    /// CPU version of the shader's search, maps two uniform numbers to a pixel
+    pub fn sample(&self, u1: f32, u2: f32) -> (u32, u32) {
+        let width = self.width as usize;
+        let y = search_cdf(&self.marginal, u1);
+        let x = search_cdf(&self.conditional[y * width..(y + 1) * width], u2);
+        (x as u32, y as u32)
+    }
+
+    /// Density of `sample` with respect to the map's uv area
+    pub fn pdf(&self, x: u32, y: u32) -> f32 {
+        let (x, y, width) = (x as usize, y as usize, self.width as usize);
+        let row = &self.conditional[y * width..(y + 1) * width];
+        let step = |cdf: &[f32], i: usize| cdf[i] - if i == 0 { 0.0 } else { cdf[i - 1] };
+        step(&self.marginal, y) * self.height as f32 * step(row, x) * self.width as f32
+    }
+}
+
+/// First index whose CDF value is above u
+fn search_cdf(cdf: &[f32], u: f32) -> usize {
+    cdf.partition_point(|&c| c <= u).min(cdf.len() - 1)
+}
+
+/// Laid out as the shader's `Environment`. Disabled means the gradient sky.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EnvironmentSettings {
+    pub rotation: f32, // radians
+    pub intensity: f32,
+    pub enabled: u32,
+    _padding: u32,
+}
+
+unsafe impl bytemuck::Pod for EnvironmentSettings {}
+unsafe impl bytemuck::Zeroable for EnvironmentSettings {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_averages_down_to_the_limit() {
+        let pixels = (0..8 * 4).map(|i| [i as f32, 0.0, 0.0, 1.0]).collect();
+        let environment = Environment::new(8, 4, pixels).fit(2);
+        assert_eq!((environment.width, environment.height), (2, 1));
+        // Mean of the left half of the image: columns 0..4 of rows 0..4
+        let left: f32 = (0..4).flat_map(|y| (0..4).map(move |x| (y * 8 + x) as f32)).sum::<f32>() / 16.0;
+        assert!((environment.pixels[0][0] - left).abs() < 1e-4);
+        assert_eq!(environment.pixels[0][3], 1.0);
+    }
+
+    #[test]
+    fn distribution_follows_luminance() {
+        let mut pixels = vec![[0.1, 0.1, 0.1, 1.0]; 16 * 8];
+        pixels[3 * 16 + 5] = [100.0, 100.0, 100.0, 1.0];
+        let distribution = Environment::new(16, 8, pixels).distribution();
+        assert_eq!(*distribution.marginal.last().unwrap(), 1.0);
+        assert!(distribution.marginal.windows(2).all(|w| w[0] <= w[1]));
+
+        // The pdf is a density over uv, so it averages to one over the pixels
+        let mean: f32 = (0..8)
+            .flat_map(|y| (0..16).map(move |x| (x, y)))
+            .map(|(x, y)| distribution.pdf(x, y))
+            .sum::<f32>() / (16.0 * 8.0);
+        assert!((mean - 1.0).abs() < 1e-3);
+
+        let steps = 64;
+        let mut bright = 0;
+        for i in 0..steps {
+            for j in 0..steps {
+                let u1 = (i as f32 + 0.5) / steps as f32;
+                let u2 = (j as f32 + 0.5) / steps as f32;
+                // The bright texel's filter footprint covers its neighbours
+                let (x, y) = distribution.sample(u1, u2);
+                if x.abs_diff(5) <= 1 && y.abs_diff(3) <= 1 {
+                    bright += 1;
+                }
+            }
+        }
+        assert!(bright > steps * steps / 2);
+    }
+}
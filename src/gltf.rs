@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::path::Path;
+use log::*;
+use nalgebra::{Matrix4, Vector2, Vector3, Vector4};
+use crate::hitable::*;
+use crate::scene::*;
+
+/// Loads the default scene of a glTF 2.0 file (`.gltf` or `.glb`). Every
+/// glTF mesh is uploaded once and instanced by the nodes that reference it,
+/// metallic-roughness materials are mapped onto the shader's material kinds
+/// and the first camera found in the node hierarchy becomes the scene camera.
+pub fn load_gltf<P: AsRef<Path>>(path: P) -> Result<Scene, ::gltf::Error> {
+    let path = path.as_ref();
+    info!("Loading {:?}", path);
+    let (document, buffers, images) = ::gltf::import(path)?;
+    let mut loader = Loader {
+        buffers: &buffers,
+        images: &images,
+        textures: HashMap::new(),
+        meshes: HashMap::new(),
+        scene: Scene::default(),
+        found_camera: false,
+    };
+    let gltf_scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next());
+    if let Some(gltf_scene) = gltf_scene {
+        for node in gltf_scene.nodes() {
+            loader.visit(&node, &Matrix4::identity());
+        }
+    }
+    if !loader.found_camera {
+        info!("No camera in {:?}, using the default", path);
+    }
+    Ok(loader.scene)
+}
+
+struct Loader<'a> {
+    buffers: &'a [::gltf::buffer::Data],
+    images: &'a [::gltf::image::Data],
+    textures: HashMap<usize, Texture>, // image index -> uploaded texture
+    meshes: HashMap<usize, Vec<MeshId>>, // mesh index -> one mesh per primitive
+    scene: Scene,
+    found_camera: bool,
+}
+
+impl Loader<'_> {
+    fn visit(&mut self, node: &::gltf::Node, parent: &Matrix4<f32>) {
+        let local = Matrix4::from(node.transform().matrix());
+        let world = parent * local;
+        if let Some(mesh) = node.mesh() {
+            if !self.meshes.contains_key(&mesh.index()) {
+                let ids = self.load_mesh(&mesh);
+                self.meshes.insert(mesh.index(), ids);
+            }
+            for &id in &self.meshes[&mesh.index()] {
+                self.scene.hitable_list.push_instance(id, world, None);
+            }
+        }
+        if let Some(camera) = node.camera() {
+            self.load_camera(&camera, &world);
+        }
+        for child in node.children() {
+            self.visit(&child, &world);
+        }
+    }
+
+    fn load_camera(&mut self, camera: &::gltf::Camera, world: &Matrix4<f32>) {
+        if self.found_camera {
+            return;
+        }
+        let ::gltf::camera::Projection::Perspective(perspective) = camera.projection() else {
+            warn!("Skipping orthographic camera {:?}", camera.name());
+            return;
+        };
+        // Strip any scale so only the orientation is kept
+        let mut rotation = Matrix4::identity();
+        for i in 0..3 {
+            let axis = world.fixed_view::<3, 1>(0, i).normalize();
+            rotation.fixed_view_mut::<3, 1>(0, i).copy_from(&axis);
+        }
+        self.scene.camera = SceneCamera {
+            center: world.fixed_view::<3, 1>(0, 3).into(),
+            rotation,
+            vfov: perspective.yfov().to_degrees(),
+        };
+        self.found_camera = true;
+    }
+
+    fn load_mesh(&mut self, mesh: &::gltf::Mesh) -> Vec<MeshId> {
+        let mut ids = Vec::new();
+        for primitive in mesh.primitives() {
+            if primitive.mode() != ::gltf::mesh::Mode::Triangles {
+                warn!("Skipping non-triangle primitive in mesh {:?}", mesh.name());
+                continue;
+            }
+            let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+            let Some(positions) = reader.read_positions() else {
+                continue;
+            };
+            let positions: Vec<Vector3<f32>> = positions.map(Vector3::from).collect();
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+            let indices: Vec<[u32; 3]> = indices
+                .chunks_exact(3)
+                .map(|t| [t[0], t[1], t[2]])
+                .collect();
+            if indices.is_empty() {
+                continue;
+            }
+
+            let gltf_material = primitive.material();
+            let pbr = gltf_material.pbr_metallic_roughness();
+            let mut converted = Mesh::new(positions, indices, self.convert_material(&gltf_material));
+            if let Some(normals) = reader.read_normals() {
+                converted = converted.with_normals(normals.map(Vector3::from).collect());
+            }
+            let tex_coord = pbr.base_color_texture().map_or(0, |info| info.tex_coord());
+            if let Some(uvs) = reader.read_tex_coords(tex_coord) {
+                converted = converted.with_uvs(uvs.into_f32().map(Vector2::from).collect());
+            }
+            ids.push(self.scene.hitable_list.add_mesh(converted));
+        }
+        ids
+    }
+
+    /// Transmissive materials become DIELECTRIC, metals METAL and everything
+    /// else LAMBERTIAN, all tinted by the base color factor and texture and
+    /// emitting the emissive factor.
+    fn convert_material(&mut self, material: &::gltf::Material) -> Material {
+        let pbr = material.pbr_metallic_roughness();
+        let base_color = Vector4::from(pbr.base_color_factor()).xyz();
+        let transmission = material.transmission().map_or(0.0, |t| t.transmission_factor());
+        let kind = if transmission >= 0.5 {
+            DIELECTRIC
+        } else if pbr.metallic_factor() >= 0.5 {
+            METAL
+        } else {
+            LAMBERTIAN
+        };
+        let converted = Material::new(base_color, kind)
+            .with_ior(material.ior().unwrap_or(1.5))
+            .with_roughness(pbr.roughness_factor())
+            .with_emission(material.emissive_factor().into(), material.emissive_strength().unwrap_or(1.0));
+        match pbr.base_color_texture() {
+            Some(info) => {
+                let texture = self.load_texture(info.texture().source().index());
+                converted.with_texture(texture)
+            }
+            None => converted,
+        }
+    }
+
+    fn load_texture(&mut self, image_index: usize) -> Texture {
+        if let Some(texture) = self.textures.get(&image_index) {
+            return *texture;
+        }
+        let image = &self.images[image_index];
+        let rgba = image_to_rgba8(image);
+        let texture = self.scene.hitable_list.add_texture(image.width, image.height, &rgba);
+        self.textures.insert(image_index, texture);
+        texture
+    }
+}
+
+fn image_to_rgba8(image: &::gltf::image::Data) -> Vec<[u8; 4]> {
+    use ::gltf::image::Format;
+    let pixels = &image.pixels;
+    match image.format {
+        Format::R8 => pixels.iter().map(|&r| [r, r, r, 255]).collect(),
+        Format::R8G8 => pixels.chunks_exact(2).map(|p| [p[0], p[1], 0, 255]).collect(),
+        Format::R8G8B8 => pixels.chunks_exact(3).map(|p| [p[0], p[1], p[2], 255]).collect(),
+        Format::R8G8B8A8 => pixels.chunks_exact(4).map(|p| [p[0], p[1], p[2], p[3]]).collect(),
+        // Higher precision formats are rare for color textures, keep the most significant byte
+        Format::R16 => pixels.chunks_exact(2).map(|p| [p[1], p[1], p[1], 255]).collect(),
+        Format::R16G16 => pixels.chunks_exact(4).map(|p| [p[1], p[3], 0, 255]).collect(),
+        Format::R16G16B16 => pixels.chunks_exact(6).map(|p| [p[1], p[3], p[5], 255]).collect(),
+        Format::R16G16B16A16 => pixels.chunks_exact(8).map(|p| [p[1], p[3], p[5], p[7]]).collect(),
+        Format::R32G32B32FLOAT | Format::R32G32B32A32FLOAT => {
+            let channels = if image.format == Format::R32G32B32FLOAT { 3 } else { 4 };
+            pixels
+                .chunks_exact(4 * channels)
+                .map(|p| {
+                    let channel = |i: usize| {
+                        let linear = f32::from_le_bytes([p[4 * i], p[4 * i + 1], p[4 * i + 2], p[4 * i + 3]]);
+                        (linear.clamp(0.0, 1.0).powf(1.0 / 2.2) * 255.0) as u8
+                    };
+                    [channel(0), channel(1), channel(2), 255]
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One triangle instanced twice under a scaled parent, a mesh per
+    /// material kind and a scaled, rotated camera
+    const GLTF: &str = r#"{
+        "asset": {"version": "2.0"},
+        "extensionsUsed": ["KHR_materials_transmission", "KHR_materials_ior", "KHR_materials_emissive_strength"],
+        "buffers": [{"byteLength": 36, "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA"}],
+        "bufferViews": [{"buffer": 0, "byteLength": 36}],
+        "accessors": [{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0]}],
+        "materials": [
+            {"pbrMetallicRoughness": {"baseColorFactor": [0.5, 0.6, 0.7, 1.0], "metallicFactor": 0.0}},
+            {"pbrMetallicRoughness": {"baseColorFactor": [0.9, 0.8, 0.7, 1.0], "metallicFactor": 1.0, "roughnessFactor": 0.25}},
+            {"pbrMetallicRoughness": {"metallicFactor": 0.0}, "extensions": {
+                "KHR_materials_transmission": {"transmissionFactor": 1.0},
+                "KHR_materials_ior": {"ior": 1.45}
+            }},
+            {"pbrMetallicRoughness": {"metallicFactor": 0.0}, "emissiveFactor": [1.0, 0.5, 0.25], "extensions": {
+                "KHR_materials_emissive_strength": {"emissiveStrength": 4.0}
+            }}
+        ],
+        "meshes": [
+            {"primitives": [{"attributes": {"POSITION": 0}, "material": 0}]},
+            {"primitives": [{"attributes": {"POSITION": 0}, "material": 1}]},
+            {"primitives": [{"attributes": {"POSITION": 0}, "material": 2}]},
+            {"primitives": [{"attributes": {"POSITION": 0}, "material": 3}]}
+        ],
+        "cameras": [{"type": "perspective", "perspective": {"yfov": 0.5, "znear": 0.1}}],
+        "nodes": [
+            {"translation": [0, 0, -5], "scale": [2, 2, 2], "children": [1, 2]},
+            {"mesh": 0, "translation": [1, 0, 0]},
+            {"mesh": 0, "translation": [0, 1, 0]},
+            {"mesh": 1},
+            {"mesh": 2},
+            {"mesh": 3},
+            {"camera": 0, "translation": [0, 1, 4], "rotation": [0, 0.70710678, 0, 0.70710678], "scale": [3, 3, 3]}
+        ],
+        "scenes": [{"nodes": [0, 3, 4, 5, 6]}],
+        "scene": 0
+    }"#;
+
+    /// Loads `GLTF` from a directory of the calling test's own, as tests run in parallel
+    fn load_fixture(test: &str) -> Scene {
+        let directory = std::env::temp_dir().join(format!("wgsl-gltf-{}-{}", std::process::id(), test));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("scene.gltf"), GLTF).unwrap();
+        let scene = load_gltf(directory.join("scene.gltf")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        scene
+    }
+
+    #[test]
+    fn nodes_instance_their_meshes_in_world_space() {
+        let hitable_list = load_fixture("nodes_instance_their_meshes_in_world_space").hitable_list;
+        assert_eq!(hitable_list.meshes.len(), 4, "the instanced mesh is uploaded once");
+        assert_eq!(hitable_list.instances.len(), 5);
+        assert_eq!(hitable_list.vertices.len(), 4 * 3);
+
+        let [first, second] = [0, 1].map(|i| hitable_list.instances[i]);
+        assert_eq!(first.blas_root, second.blas_root);
+        // The parent's scale applies to the children's translations too
+        let corner = |instance: &Instance, x, y| instance.object_to_world.transform_point(&[x, y, 0.0].into()).coords;
+        assert_eq!(corner(&first, 0.0, 0.0), Vector3::new(2.0, 0.0, -5.0));
+        assert_eq!(corner(&first, 1.0, 0.0), Vector3::new(4.0, 0.0, -5.0));
+        assert_eq!(corner(&second, 0.0, 1.0), Vector3::new(0.0, 4.0, -5.0));
+    }
+
+    #[test]
+    fn the_first_camera_keeps_its_orientation_but_not_its_scale() {
+        let camera = load_fixture("the_first_camera_keeps_its_orientation_but_not_its_scale").camera;
+        assert_eq!(camera.center, Vector3::new(0.0, 1.0, 4.0));
+        assert!((camera.vfov - 0.5_f32.to_degrees()).abs() < 1e-4);
+        // A quarter turn about y takes x to -z
+        let x = camera.rotation.transform_vector(&Vector3::x());
+        assert!((x - Vector3::new(0.0, 0.0, -1.0)).norm() < 1e-6, "{}", x);
+        let y = camera.rotation.transform_vector(&Vector3::y());
+        assert!((y - Vector3::y()).norm() < 1e-6, "{}", y);
+    }
+
+    #[test]
+    fn pbr_parameters_pick_the_material_kind() {
+        let hitable_list = load_fixture("pbr_parameters_pick_the_material_kind").hitable_list;
+        let [plain, _, metal, glass, lamp] = [0, 1, 2, 3, 4].map(|i| hitable_list.hitables[i].material);
+
+        assert_eq!(plain.kind(), LAMBERTIAN);
+        assert_eq!(plain.albedo(), Vector3::new(0.5, 0.6, 0.7));
+
+        assert_eq!(metal.kind(), METAL);
+        assert_eq!(metal.albedo(), Vector3::new(0.9, 0.8, 0.7));
+        assert_eq!(metal.roughness(), 0.25);
+
+        assert_eq!(glass.kind(), DIELECTRIC);
+        assert_eq!(glass.ior(), 1.45);
+
+        assert_eq!(lamp.kind(), LAMBERTIAN);
+        assert_eq!(lamp.emission, Vector3::new(4.0, 2.0, 1.0));
+    }
+}
@@ -0,0 +1,465 @@
+use std::path::{Path, PathBuf};
+use crate::reference::linear_to_srgb;
+use crate::scene::RenderSettings;
+
+/// Set to rewrite every reference image with what is rendered now
+pub const UPDATE_VARIABLE: &str = "WGSL_UPDATE_GOLDEN";
+
+/// Renders golden scenes 64 pixels wide and with a fixed sample count,
+/// whatever their own files ask for. The random numbers only depend on the
+/// pixel and sample, so the same settings always trace the same paths.
+pub fn golden_settings(settings: RenderSettings) -> RenderSettings {
+    RenderSettings {
+        width: 64,
+        height: (64 * settings.height / settings.width.max(1)).max(1),
+        samples_per_pixel: 4,
+        iterations: 4,
+        ..settings
+    }
+}
+
+/// How far a render may drift from its reference, in linear [0, 1] RGB for
+/// the RMSE and in FLIP units, 0 for identical and 1 for as different as
+/// can be, for the mean
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tolerance {
+    pub rmse: f32,
+    pub mean_flip: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        // The integrators and the CPU reference differ by up to 0.002 and
+        // 0.015 on the golden scenes. A 2% zoom, a faint glow or another
+        // sample per pixel are well beyond both.
+        Self {
+            rmse: 0.005,
+            mean_flip: 0.02,
+        }
+    }
+}
+
+/// Differences between a render and its reference
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub rmse: f32,
+    pub mean_flip: f32,
+    pub flip: image::ImageBuffer<image::Luma<f32>, Vec<f32>>, // per pixel
+}
+
+impl Comparison {
+    pub fn within(&self, tolerance: &Tolerance) -> bool {
+        self.rmse <= tolerance.rmse && self.mean_flip <= tolerance.mean_flip
+    }
+
+    /// The FLIP error of every pixel, black where they agree through red
+    /// and yellow to white
+    pub fn heatmap(&self) -> image::RgbImage {
+        image::RgbImage::from_fn(self.flip.width(), self.flip.height(), |x, y| {
+            let e = self.flip.get_pixel(x, y).0[0].clamp(0.0, 1.0) * 3.0;
+            image::Rgb([e, e - 1.0, e - 2.0].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8))
+        })
+    }
+}
+
+/// Compares two displayable images, both sRGB encoded
+pub fn compare(image: &image::RgbaImage, reference: &image::RgbaImage) -> Comparison {
+    assert_eq!(image.dimensions(), reference.dimensions(), "images differ in size");
+    let (test, reference) = (LinearImage::from_srgb(image), LinearImage::from_srgb(reference));
+    let squared: f32 = test.pixels
+        .iter()
+        .zip(&reference.pixels)
+        .flat_map(|(a, b)| (0..3).map(move |c| (a[c] - b[c]).powi(2)))
+        .sum();
+    let flip = flip(&test, &reference);
+    let count = flip.len() as f32;
+    Comparison {
+        rmse: (squared / (3.0 * count)).sqrt(),
+        mean_flip: flip.iter().sum::<f32>() / count,
+        flip: image::ImageBuffer::from_raw(test.width, test.height, flip).unwrap(),
+    }
+}
+
+/// Reference images live in one directory as `<name>.png`, renders that
+/// don't match them are written to another with their FLIP heatmaps
+pub struct GoldenTest {
+    references: PathBuf,
+    output: PathBuf,
+    tolerance: Tolerance,
+    update: bool,
+}
+
+impl GoldenTest {
+    /// Updates the references when `UPDATE_VARIABLE` is set
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(references: P, output: Q) -> Self {
+        Self {
+            references: references.as_ref().to_path_buf(),
+            output: output.as_ref().to_path_buf(),
+            tolerance: Tolerance::default(),
+            update: std::env::var_os(UPDATE_VARIABLE).is_some(),
+        }
+    }
+
+    pub fn with_tolerance(mut self, tolerance: Tolerance) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn with_update(mut self, update: bool) -> Self {
+        self.update = update;
+        self
+    }
+
+    /// `<name>.exr` holding linear radiance when there is one, otherwise
+    /// the sRGB `<name>.png`
+    pub fn reference_path(&self, name: &str) -> PathBuf {
+        let exr = self.references.join(format!("{}.exr", name));
+        if exr.exists() {
+            exr
+        } else {
+            self.references.join(format!("{}.png", name))
+        }
+    }
+
+    /// Compares a render with its reference. A missing reference is written
+    /// from the render and still fails, so new ones get looked at before
+    /// they are checked in.
+    pub fn check(&self, name: &str, image: &image::RgbaImage) -> Result<Comparison, String> {
+        let path = self.reference_path(name);
+        if self.update || !path.exists() {
+            let missing = !path.exists();
+            std::fs::create_dir_all(&self.references).map_err(|err| err.to_string())?;
+            save_reference(image, &path).map_err(|err| format!("{}: {}", path.display(), err))?;
+            if missing && !self.update {
+                return Err(format!("{}: no reference, wrote {}", name, path.display()));
+            }
+            return Ok(compare(image, image));
+        }
+
+        let reference = load_reference(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        if reference.dimensions() != image.dimensions() {
+            return Err(format!("{}: rendered {:?} but the reference is {:?}", name, image.dimensions(), reference.dimensions()));
+        }
+        let comparison = compare(image, &reference);
+        if comparison.within(&self.tolerance) {
+            return Ok(comparison);
+        }
+
+        std::fs::create_dir_all(&self.output).map_err(|err| err.to_string())?;
+        let rendered = self.output.join(format!("{}.png", name));
+        let diff = self.output.join(format!("{}-diff.png", name));
+        image.save(&rendered).map_err(|err| format!("{}: {}", rendered.display(), err))?;
+        comparison.heatmap().save(&diff).map_err(|err| format!("{}: {}", diff.display(), err))?;
+        Err(format!(
+            "{}: RMSE {:.4} (at most {}), mean FLIP {:.4} (at most {}), see {} and {}",
+            name,
+            comparison.rmse,
+            self.tolerance.rmse,
+            comparison.mean_flip,
+            self.tolerance.mean_flip,
+            rendered.display(),
+            diff.display(),
+        ))
+    }
+}
+
+fn is_exr(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "exr")
+}
+
+// EXR references hold linear radiance, shown as the renderer shows it
+fn load_reference(path: &Path) -> Result<image::RgbaImage, image::ImageError> {
+    let image = image::open(path)?;
+    if !is_exr(path) {
+        return Ok(image.into_rgba8());
+    }
+    let linear = image.into_rgba32f();
+    Ok(image::RgbaImage::from_fn(linear.width(), linear.height(), |x, y| {
+        image::Rgba(linear.get_pixel(x, y).0.map(|c| (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0).round() as u8))
+    }))
+}
+
+fn save_reference(image: &image::RgbaImage, path: &Path) -> Result<(), image::ImageError> {
+    if !is_exr(path) {
+        return image.save(path);
+    }
+    let linear = LinearImage::from_srgb(image);
+    let pixels = linear.pixels.iter().flat_map(|&[r, g, b]| [r, g, b, 1.0]).collect();
+    image::Rgba32FImage::from_raw(linear.width, linear.height, pixels).unwrap().save(path)
+}
+
+struct LinearImage {
+    width: u32,
+    height: u32,
+    pixels: Vec<[f32; 3]>,
+}
+
+impl LinearImage {
+    fn from_srgb(image: &image::RgbaImage) -> Self {
+        let decode = |c: u8| {
+            let c = c as f32 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        Self {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.pixels().map(|p| [0, 1, 2].map(|c| decode(p.0[c]))).collect(),
+        }
+    }
+}
+
+// LDR FLIP (Andersson et al. 2020): colour differences of the images as seen
+// from a desktop monitor, boosted where edges or points differ. Images are
+// seen at 67 pixels per degree, a 0.7 m wide 4K monitor from 0.7 m away.
+const PIXELS_PER_DEGREE: f32 = 67.0206;
+const QC: f32 = 0.7;
+const PC: f32 = 0.4;
+const PT: f32 = 0.95;
+const QF: f32 = 0.5;
+const FEATURE_WIDTH: f32 = 0.082; // degrees
+
+fn flip(test: &LinearImage, reference: &LinearImage) -> Vec<f32> {
+    let (width, height) = (test.width as usize, test.height as usize);
+    let filtered = |image: &LinearImage| -> Vec<[f32; 3]> {
+        let opponent: Vec<[f32; 3]> = image.pixels.iter().map(|&p| xyz_to_ycxcz(rgb_to_xyz(p))).collect();
+        // Each channel blurred by the eye's contrast sensitivity for it
+        let channels: Vec<Vec<f32>> = (0..3)
+            .map(|c| {
+                let channel: Vec<f32> = opponent.iter().map(|p| p[c]).collect();
+                let kernel = csf_kernel(c);
+                convolve(&channel, width, height, &kernel, &kernel)
+            })
+            .collect();
+        (0..width * height)
+            .map(|i| {
+                let rgb = xyz_to_rgb(ycxcz_to_xyz([channels[0][i], channels[1][i], channels[2][i]]));
+                hunt(xyz_to_lab(rgb_to_xyz(rgb.map(|c| c.clamp(0.0, 1.0)))))
+            })
+            .collect()
+    };
+    let (test_lab, reference_lab) = (filtered(test), filtered(reference));
+    let max_color = hyab(hunt(xyz_to_lab(rgb_to_xyz([0.0, 1.0, 0.0]))), hunt(xyz_to_lab(rgb_to_xyz([0.0, 0.0, 1.0])))).powf(QC);
+
+    let (test_edges, test_points) = features(test, width, height);
+    let (reference_edges, reference_points) = features(reference, width, height);
+
+    (0..width * height)
+        .map(|i| {
+            let distance = hyab(test_lab[i], reference_lab[i]).powf(QC);
+            let color = if distance < PC * max_color {
+                PT / (PC * max_color) * distance
+            } else {
+                PT + (distance - PC * max_color) / (max_color - PC * max_color) * (1.0 - PT)
+            };
+            let edge = (test_edges[i] - reference_edges[i]).abs();
+            let point = (test_points[i] - reference_points[i]).abs();
+            let feature = (edge.max(point) / std::f32::consts::SQRT_2).powf(QF);
+            color.powf(1.0 - feature)
+        })
+        .collect()
+}
+
+// Separable kernel for a channel: FLIP's sum of Gaussians over visual degrees
+fn csf_kernel(channel: usize) -> Vec<f32> {
+    let [(a1, b1), (a2, b2)]: [(f32, f32); 2] = match channel {
+        0 => [(1.0, 0.0047), (0.0, 1e-5)],
+        1 => [(1.0, 0.0053), (0.0, 1e-5)],
+        _ => [(34.1, 0.04), (13.5, 0.025)],
+    };
+    let pi = std::f32::consts::PI;
+    let radius = (3.0 * (b1.max(b2) / (2.0 * pi * pi)).sqrt() * PIXELS_PER_DEGREE).ceil() as i32;
+    let gaussian = |x: f32, a: f32, b: f32| a * (pi / b).sqrt() * (-pi * pi * x * x / b).exp();
+    let kernel: Vec<f32> = (-radius..=radius)
+        .map(|i| {
+            let x = i as f32 / PIXELS_PER_DEGREE;
+            gaussian(x, a1, b1) + gaussian(x, a2, b2)
+        })
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.iter().map(|k| k / sum).collect()
+}
+
+// Edge and point strength of the luminance
+fn features(image: &LinearImage, width: usize, height: usize) -> (Vec<f32>, Vec<f32>) {
+    let luminance: Vec<f32> = image.pixels.iter().map(|&p| rgb_to_xyz(p)[1]).collect();
+    let sigma = 0.5 * FEATURE_WIDTH * PIXELS_PER_DEGREE;
+    let radius = (3.0 * sigma).ceil() as i32;
+    let offsets: Vec<f32> = (-radius..=radius).map(|i| i as f32).collect();
+    let gaussian: Vec<f32> = offsets.iter().map(|x| (-x * x / (2.0 * sigma * sigma)).exp()).collect();
+    let sum: f32 = gaussian.iter().sum();
+    let gaussian: Vec<f32> = gaussian.iter().map(|g| g / sum).collect();
+    // Derivatives with their positive and negative lobes each summing to one
+    let balanced = |kernel: Vec<f32>| {
+        let positive: f32 = kernel.iter().filter(|&&k| k > 0.0).sum();
+        let negative: f32 = -kernel.iter().filter(|&&k| k < 0.0).sum::<f32>();
+        kernel.iter().map(|&k| if k > 0.0 { k / positive } else { k / negative }).collect::<Vec<f32>>()
+    };
+    let first = balanced(offsets.iter().zip(&gaussian).map(|(x, g)| -x * g).collect());
+    let second = balanced(offsets.iter().zip(&gaussian).map(|(x, g)| (x * x / (sigma * sigma) - 1.0) * g).collect());
+
+    let magnitude = |kernel: &[f32]| {
+        let dx = convolve(&luminance, width, height, kernel, &gaussian);
+        let dy = convolve(&luminance, width, height, &gaussian, kernel);
+        dx.iter().zip(&dy).map(|(x, y)| (x * x + y * y).sqrt()).collect::<Vec<f32>>()
+    };
+    (magnitude(&first), magnitude(&second))
+}
+
+// Convolves rows with `horizontal` and columns with `vertical`, both odd
+// sized and centered, clamping at the borders
+fn convolve(values: &[f32], width: usize, height: usize, horizontal: &[f32], vertical: &[f32]) -> Vec<f32> {
+    let pass = |values: &[f32], kernel: &[f32], step: (isize, isize)| -> Vec<f32> {
+        let radius = (kernel.len() / 2) as isize;
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = ((i % width) as isize, (i / width) as isize);
+                kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, weight)| {
+                        let offset = k as isize - radius;
+                        let sx = (x + offset * step.0).clamp(0, width as isize - 1);
+                        let sy = (y + offset * step.1).clamp(0, height as isize - 1);
+                        weight * values[sy as usize * width + sx as usize]
+                    })
+                    .sum()
+            })
+            .collect()
+    };
+    let rows = pass(values, horizontal, (1, 0));
+    pass(&rows, vertical, (0, 1))
+}
+
+// Linear sRGB to CIE XYZ under D65, and the white it maps (1, 1, 1) to
+const WHITE: [f32; 3] = [0.950_47, 1.0, 1.088_83];
+
+fn rgb_to_xyz([r, g, b]: [f32; 3]) -> [f32; 3] {
+    [
+        0.412_456_4 * r + 0.357_576_1 * g + 0.180_437_5 * b,
+        0.212_672_9 * r + 0.715_152_2 * g + 0.072_175 * b,
+        0.019_333_9 * r + 0.119_192 * g + 0.950_304_1 * b,
+    ]
+}
+
+fn xyz_to_rgb([x, y, z]: [f32; 3]) -> [f32; 3] {
+    [
+        3.240_454_2 * x - 1.537_138_5 * y - 0.498_531_4 * z,
+        -0.969_266 * x + 1.876_010_8 * y + 0.041_556 * z,
+        0.055_643_4 * x - 0.204_025_9 * y + 1.057_225_2 * z,
+    ]
+}
+
+// The linear opponent space FLIP filters in
+fn xyz_to_ycxcz(xyz: [f32; 3]) -> [f32; 3] {
+    let [x, y, z] = [0, 1, 2].map(|i| xyz[i] / WHITE[i]);
+    [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+}
+
+fn ycxcz_to_xyz([l, cx, cz]: [f32; 3]) -> [f32; 3] {
+    let y = (l + 16.0) / 116.0;
+    [(y + cx / 500.0) * WHITE[0], y * WHITE[1], (y - cz / 200.0) * WHITE[2]]
+}
+
+fn xyz_to_lab(xyz: [f32; 3]) -> [f32; 3] {
+    let delta = 6.0f32 / 29.0;
+    let f = |t: f32| {
+        if t > delta.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * delta * delta) + 4.0 / 29.0
+        }
+    };
+    let [x, y, z] = [0, 1, 2].map(|i| f(xyz[i] / WHITE[i]));
+    [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+}
+
+// Dark colours look less saturated (the Hunt effect)
+fn hunt([l, a, b]: [f32; 3]) -> [f32; 3] {
+    [l, 0.01 * l * a, 0.01 * l * b]
+}
+
+fn hyab(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (a[0] - b[0]).abs() + ((a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checker(width: u32, height: u32, dark: u8, light: u8) -> image::RgbaImage {
+        image::RgbaImage::from_fn(width, height, |x, y| {
+            let v = if (x / 4 + y / 4) % 2 == 0 { dark } else { light };
+            image::Rgba([v, v, v, 255])
+        })
+    }
+
+    #[test]
+    fn identical_images_have_no_error() {
+        let image = checker(24, 16, 20, 230);
+        let comparison = compare(&image, &image);
+        assert_eq!(comparison.rmse, 0.0);
+        assert!(comparison.flip.pixels().all(|p| p.0[0] == 0.0));
+        assert!(comparison.within(&Tolerance::default()));
+        assert!(comparison.heatmap().pixels().all(|p| p.0 == [0, 0, 0]));
+    }
+
+    #[test]
+    fn errors_grow_with_the_difference() {
+        let reference = checker(24, 16, 20, 230);
+        let comparison = |image: &image::RgbaImage| compare(image, &reference);
+        // Rounding differences in a few pixels, like another GPU's
+        let mut nudged = reference.clone();
+        for pixel in nudged.pixels_mut().step_by(7) {
+            pixel.0[0] += 1;
+        }
+        assert!(comparison(&nudged).within(&Tolerance::default()));
+
+        let off_by_one = comparison(&checker(24, 16, 21, 229));
+        let faded = comparison(&checker(24, 16, 80, 170));
+        let inverted = comparison(&checker(24, 16, 230, 20));
+        assert!(!faded.within(&Tolerance::default()));
+        assert!(off_by_one.mean_flip < faded.mean_flip && faded.mean_flip < inverted.mean_flip);
+        assert!(off_by_one.rmse < faded.rmse && faded.rmse < inverted.rmse);
+        // Black against white is about as bad as it gets
+        let black = image::RgbaImage::from_pixel(8, 8, image::Rgba([0, 0, 0, 255]));
+        let white = image::RgbaImage::from_pixel(8, 8, image::Rgba([255, 255, 255, 255]));
+        let opposite = compare(&black, &white);
+        assert!((opposite.rmse - 1.0).abs() < 1e-6);
+        assert!(opposite.mean_flip > 0.9, "{}", opposite.mean_flip);
+    }
+
+    #[test]
+    fn exr_references_hold_linear_radiance() {
+        let directory = std::env::temp_dir().join(format!("wgsl-golden-exr-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("checker.exr");
+        let image = checker(8, 8, 20, 230);
+        save_reference(&image, &path).unwrap();
+        let linear = image::open(&path).unwrap().into_rgba32f();
+        assert!((linear.get_pixel(0, 0).0[0] - 0.006_995).abs() < 1e-5);
+        assert_eq!(load_reference(&path).unwrap(), image);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn failures_leave_the_render_and_a_heatmap() {
+        let directory = std::env::temp_dir().join(format!("wgsl-golden-{}", std::process::id()));
+        let golden = GoldenTest::new(directory.join("references"), directory.join("output")).with_update(false);
+        let reference = checker(16, 16, 20, 230);
+        // The first check writes the reference but fails until it's checked in
+        assert!(golden.check("checker", &reference).is_err());
+        assert!(golden.reference_path("checker").exists());
+        assert!(golden.check("checker", &reference).is_ok());
+
+        let error = golden.check("checker", &checker(16, 16, 230, 20)).unwrap_err();
+        assert!(error.contains("mean FLIP"), "{}", error);
+        let diff = image::open(directory.join("output/checker-diff.png")).unwrap().into_rgb8();
+        assert!(diff.pixels().any(|p| p.0[0] > 0));
+        assert!(directory.join("output/checker.png").exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
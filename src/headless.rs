@@ -0,0 +1,55 @@
+use std::path::Path;
+use log::*;
+use crate::reference::ReferenceRenderer;
+use crate::renderer::{AdapterPreference, Integrator, Renderer};
+use crate::scene::Scene;
+
+/// Creates a renderer drawing into an offscreen texture, without a window
+/// or surface. Its size comes from the scene's `RenderSettings`, and without
+/// an integrator the adapter's best supported one is used.
+pub async fn offscreen_renderer(scene: Scene, adapter: AdapterPreference, integrator: Option<Integrator>) -> Renderer {
+    let instance = wgpu::Instance::default();
+    let adapter = adapter.request_adapter(&instance, None).await;
+    offscreen_renderer_on(scene, &adapter, integrator).await
+}
+
+/// `offscreen_renderer` on an adapter the caller already has
+pub async fn offscreen_renderer_on(scene: Scene, adapter: &wgpu::Adapter, integrator: Option<Integrator>) -> Renderer {
+    info!("Rendering headless on {:?}", adapter.get_info());
+    let device = Renderer::request_device(adapter, integrator).await;
+    let (width, height) = (scene.settings.width, scene.settings.height);
+    Renderer::for_texture(device, scene, width, height)
+}
+
+/// Renders the scene offscreen for the configured number of iterations
+/// and reads the accumulated image back. Machines without such an adapter
+/// render on the CPU with the `ReferenceRenderer` instead.
+pub async fn render_image(scene: Scene, adapter: AdapterPreference, integrator: Option<Integrator>) -> image::RgbaImage {
+    let instance = wgpu::Instance::default();
+    match adapter.try_request_adapter(&instance, None).await {
+        Some(adapter) => render_image_on(scene, &adapter, integrator).await,
+        None => {
+            warn!("No {:?} adapter found, rendering on the CPU", adapter);
+            ReferenceRenderer::new(scene).render_image()
+        }
+    }
+}
+
+/// `render_image` on an adapter the caller already has
+pub async fn render_image_on(scene: Scene, adapter: &wgpu::Adapter, integrator: Option<Integrator>) -> image::RgbaImage {
+    let renderer = offscreen_renderer_on(scene, adapter, integrator).await;
+    converge(renderer)
+}
+
+fn converge(mut renderer: Renderer) -> image::RgbaImage {
+    while !renderer.is_converged() {
+        renderer.step().expect("texture targets don't fail to render");
+    }
+    renderer.read_image().expect("texture targets can be read back")
+}
+
+/// Renders the scene headless and writes it out as a PNG
+pub fn render_to_png<P: AsRef<Path>>(scene: Scene, adapter: AdapterPreference, integrator: Option<Integrator>, path: P) -> Result<(), image::ImageError> {
+    let image = pollster::block_on(render_image(scene, adapter, integrator));
+    image.save_with_format(path, image::ImageFormat::Png)
+}
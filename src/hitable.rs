@@ -1,12 +1,23 @@
-use nalgebra::Vector3;
+use log::*;
+use nalgebra::{Matrix4, Vector2, Vector3};
+use crate::bvh::{offset_nodes, traverse, Aabb, Bvh, BvhNode};
+
+pub const SPHERE: u32 = 0;
+pub const MESH: u32 = 1;
+
+pub const LAMBERTIAN: u32 = 0;
+pub const METAL: u32 = 1;
+pub const DIELECTRIC: u32 = 2;
+pub const EMISSIVE: u32 = 3;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct Hitable {
-    kind: u32,
-    _padding: [u32; 3], // Padding to align with the next field
-    sphere: Sphere,
-    material: Material,
+    pub kind: u32,
+    pub instance: u32,   // Mesh only: index into the instance buffer
+    _padding: [u32; 2],  // Padding to align with the next field
+    pub sphere: Sphere,  // For meshes this is the world space bounding sphere
+    pub material: Material,
 }
 
 
@@ -14,21 +25,42 @@ impl Hitable {
     pub fn new(kind: u32, sphere: Sphere, material: Material) -> Self {
         Self {
             kind,
-            _padding: [0; 3],
+            instance: 0,
+            _padding: [0; 2],
             sphere,
             material,
         }
     }
 }
 
+/// CPU mirror of the shader's `Ray`
+#[derive(Copy, Clone, Debug)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn new(origin: Vector3<f32>, direction: Vector3<f32>) -> Self {
+        Self {
+            origin,
+            direction,
+        }
+    }
+
+    pub fn at(&self, t: f32) -> Vector3<f32> {
+        self.origin + t * self.direction
+    }
+}
+
 unsafe impl bytemuck::Pod for Hitable {}
 unsafe impl bytemuck::Zeroable for Hitable {}
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug,)]
 pub struct Sphere {
-    center: Vector3<f32>,
-    radius: f32,
+    pub center: Vector3<f32>,
+    pub radius: f32,
 }
 
 impl Sphere {
@@ -38,16 +70,94 @@ impl Sphere {
             radius,
         }
     }
+
+    /// Same root selection as `hit_sphere` in the shader
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<f32> {
+        let oc = self.center - ray.origin;
+        let a = ray.direction.dot(&ray.direction);
+        let half_b = oc.dot(&ray.direction);
+        let c = oc.dot(&oc) - self.radius * self.radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let sqrtd = discriminant.sqrt();
+        let mut root = (half_b - sqrtd) / a;
+        if root <= t_min || t_max <= root {
+            root = (half_b + sqrtd) / a;
+            if root <= t_min || t_max <= root {
+                return None;
+            }
+        }
+        Some(root)
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let extent = Vector3::repeat(self.radius.abs());
+        Aabb::new(self.center - extent, self.center + extent)
+    }
+}
+
+/// Möller–Trumbore intersection as in the shader's `intersect_triangle`,
+/// returns t and the barycentric weights of p1 and p2.
+pub fn intersect_triangle(ray: &Ray, p0: Vector3<f32>, p1: Vector3<f32>, p2: Vector3<f32>) -> Option<(f32, f32, f32)> {
+    let edge1 = p1 - p0;
+    let edge2 = p2 - p0;
+    let pvec = ray.direction.cross(&edge2);
+    let det = edge1.dot(&pvec);
+    if det.abs() < 1e-8 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let tvec = ray.origin - p0;
+    let b1 = tvec.dot(&pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let qvec = tvec.cross(&edge1);
+    let b2 = ray.direction.dot(&qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    Some((edge2.dot(&qvec) * inv_det, b1, b2))
 }
 
 unsafe impl bytemuck::Pod for Sphere {}
 unsafe impl bytemuck::Zeroable for Sphere {}
 
+/// CPU mirror of the shader's `HitRecord`, the normal facing against the ray
+#[derive(Copy, Clone, Debug)]
+pub struct HitRecord {
+    pub t: f32,
+    pub p: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub front_face: bool,
+    pub material: Material, // with any texture baked into the albedo
+    pub shape: u32, // SPHERE or MESH
+    pub size: f32, // sphere radius, or world space area of the hit triangle
+    pub face_normal: Vector3<f32>, // geometric normal, never interpolated or flipped
+}
+
+impl HitRecord {
+    // The shader's `set_front_face`
+    fn facing(mut self, ray: &Ray) -> Self {
+        self.front_face = ray.direction.dot(&self.normal) < 0.0;
+        if !self.front_face {
+            self.normal = -self.normal;
+        }
+        self
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct Material {
     albedo: Vector3<f32>,
     kind: u32,
+    texture: Texture, // multiplies albedo, width 0 means untextured
+    ior: f32,         // index of refraction, dielectrics only
+    pub emission: Vector3<f32>, // emitted radiance, added on top of any kind
+    roughness: f32,   // 0 is a perfect mirror, metals only
 }
 
 impl Material {
@@ -55,9 +165,495 @@ impl Material {
         Self {
             albedo,
             kind,
+            texture: Texture::NONE,
+            ior: 1.5,
+            emission: Vector3::zeros(),
+            roughness: 0.0,
         }
     }
+
+    /// A light that emits `color * intensity` and doesn't reflect anything
+    pub fn emissive(color: Vector3<f32>, intensity: f32) -> Self {
+        Self::new(Vector3::zeros(), EMISSIVE).with_emission(color, intensity)
+    }
+
+    /// Clear glass-like material with the given index of refraction
+    pub fn dielectric(ior: f32) -> Self {
+        Self::new(Vector3::new(1.0, 1.0, 1.0), DIELECTRIC).with_ior(ior)
+    }
+
+    pub fn with_ior(mut self, ior: f32) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    /// Makes any material glow, front faces emit `color * intensity`
+    pub fn with_emission(mut self, color: Vector3<f32>, intensity: f32) -> Self {
+        self.emission = color * intensity;
+        self
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission.max() > 0.0
+    }
+
+    /// How far reflections are scattered from the mirror direction, clamped to [0, 1]
+    pub fn with_roughness(mut self, roughness: f32) -> Self {
+        self.roughness = roughness.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_texture(mut self, texture: Texture) -> Self {
+        self.texture = texture;
+        self
+    }
+
+    pub fn albedo(&self) -> Vector3<f32> {
+        self.albedo
+    }
+
+    pub fn kind(&self) -> u32 {
+        self.kind
+    }
+
+    pub fn ior(&self) -> f32 {
+        self.ior
+    }
+
+    pub fn roughness(&self) -> f32 {
+        self.roughness
+    }
 }
 
 unsafe impl bytemuck::Pod for Material {}
-unsafe impl bytemuck::Zeroable for Material {}
\ No newline at end of file
+unsafe impl bytemuck::Zeroable for Material {}
+
+/// A reference to an RGBA8 image stored in the texel buffer of a `HitableList`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Texture {
+    offset: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Texture {
+    pub const NONE: Texture = Texture { offset: 0, width: 0, height: 0 };
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MeshVertex {
+    position: Vector3<f32>,
+    u: f32, // stored in the padding slots to keep the vertex at 32 bytes
+    normal: Vector3<f32>, // zero means "use the face normal"
+    v: f32,
+}
+
+impl MeshVertex {
+    pub fn new(position: Vector3<f32>, normal: Vector3<f32>, uv: Vector2<f32>) -> Self {
+        Self {
+            position,
+            u: uv.x,
+            normal,
+            v: uv.y,
+        }
+    }
+}
+
+unsafe impl bytemuck::Pod for MeshVertex {}
+unsafe impl bytemuck::Zeroable for MeshVertex {}
+
+/// An indexed triangle mesh with a single material.
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    pub positions: Vec<Vector3<f32>>,
+    pub normals: Option<Vec<Vector3<f32>>>,
+    pub uvs: Option<Vec<Vector2<f32>>>,
+    pub indices: Vec<[u32; 3]>,
+    pub material: Material,
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Vector3<f32>>, indices: Vec<[u32; 3]>, material: Material) -> Self {
+        Self {
+            positions,
+            normals: None,
+            uvs: None,
+            indices,
+            material,
+        }
+    }
+
+    /// Per-vertex normals used for smooth shading; without them triangles are flat shaded.
+    pub fn with_normals(mut self, normals: Vec<Vector3<f32>>) -> Self {
+        assert_eq!(normals.len(), self.positions.len(), "need one normal per vertex");
+        self.normals = Some(normals);
+        self
+    }
+
+    /// Per-vertex texture coordinates, needed when the material is textured.
+    pub fn with_uvs(mut self, uvs: Vec<Vector2<f32>>) -> Self {
+        assert_eq!(uvs.len(), self.positions.len(), "need one uv per vertex");
+        self.uvs = Some(uvs);
+        self
+    }
+}
+
+/// Places a mesh's bottom level BVH in the world. Laid out as the shader's `Instance`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Instance {
+    pub object_to_world: Matrix4<f32>,
+    pub world_to_object: Matrix4<f32>,
+    pub blas_root: u32,
+    pub first_triangle: u32, // the triangles under blas_root, only used on the CPU
+    pub triangle_count: u32,
+    _padding: u32,
+}
+
+impl Instance {
+    pub fn to_object(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.world_to_object.transform_point(&ray.origin.into()).coords,
+            self.world_to_object.transform_vector(&ray.direction),
+        )
+    }
+}
+
+unsafe impl bytemuck::Pod for Instance {}
+unsafe impl bytemuck::Zeroable for Instance {}
+
+/// Handle to a mesh added with `HitableList::add_mesh`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MeshId(usize);
+
+#[derive(Copy, Clone, Debug)]
+pub struct MeshInfo {
+    blas_root: u32,
+    first_triangle: u32,
+    triangle_count: u32,
+    bounds: Aabb, // object space
+    material: Material,
+}
+
+/// Everything the shader needs to intersect the scene: the hitables plus
+/// the shared vertex, index and texel buffers referenced by meshes and
+/// materials, and the bottom level BVHs that meshes are instanced from.
+#[derive(Clone, Debug, Default)]
+pub struct HitableList {
+    pub hitables: Vec<Hitable>,
+    pub vertices: Vec<MeshVertex>,
+    pub indices: Vec<u32>,
+    pub texels: Vec<u32>, // sRGB encoded RGBA8, packed as in WGSL's unpack4x8unorm
+    pub meshes: Vec<MeshInfo>,
+    pub blas_nodes: Vec<BvhNode>, // child indices are relative to this buffer, leaves index triangles
+    pub instances: Vec<Instance>,
+}
+
+impl HitableList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, hitable: Hitable) {
+        self.hitables.push(hitable);
+    }
+
+    /// Adds a single instance of the mesh at its own coordinates.
+    pub fn push_mesh(&mut self, mesh: Mesh) {
+        let id = self.add_mesh(mesh);
+        self.push_instance(id, Matrix4::identity(), None);
+    }
+
+    /// Uploads the mesh geometry and builds its bottom level BVH without
+    /// placing it in the scene, see `push_instance`.
+    pub fn add_mesh(&mut self, mesh: Mesh) -> MeshId {
+        assert!(!mesh.indices.is_empty(), "meshes need at least one triangle");
+        let base_vertex = self.vertices.len() as u32;
+        let first_triangle = (self.indices.len() / 3) as u32;
+        for (i, position) in mesh.positions.iter().enumerate() {
+            let normal = match &mesh.normals {
+                Some(normals) => normals[i],
+                None => Vector3::zeros(),
+            };
+            let uv = match &mesh.uvs {
+                Some(uvs) => uvs[i],
+                None => Vector2::zeros(),
+            };
+            self.vertices.push(MeshVertex::new(*position, normal, uv));
+        }
+
+        let triangle_bounds = mesh.indices
+            .iter()
+            .map(|triangle| {
+                let mut bounds = Aabb::empty();
+                for &i in triangle {
+                    bounds.grow(&mesh.positions[i as usize]);
+                }
+                bounds
+            })
+            .collect();
+        let (blas, order) = Bvh::from_bounds(triangle_bounds);
+        for &triangle in &order {
+            self.indices.extend(mesh.indices[triangle].iter().map(|i| base_vertex + i));
+        }
+        let blas_root = self.blas_nodes.len() as u32;
+        let mut nodes = blas.nodes;
+        offset_nodes(&mut nodes, blas_root, first_triangle);
+        self.meshes.push(MeshInfo {
+            blas_root,
+            first_triangle,
+            triangle_count: mesh.indices.len() as u32,
+            bounds: nodes[0].bounds(),
+            material: mesh.material,
+        });
+        self.blas_nodes.extend(nodes);
+        MeshId(self.meshes.len() - 1)
+    }
+
+    /// Places a mesh in the world, optionally replacing its material.
+    /// The transform must be invertible.
+    pub fn push_instance(&mut self, mesh: MeshId, object_to_world: Matrix4<f32>, material: Option<Material>) {
+        let info = self.meshes[mesh.0];
+        self.place(&info, object_to_world, material.unwrap_or(info.material));
+    }
+
+    /// Adds everything in `other`, such as a loaded OBJ or glTF file, moved by
+    /// `transform` and optionally with every material replaced. Spheres can't
+    /// be stretched, a non-uniform scale leaves them round with the same volume.
+    pub fn append(&mut self, other: HitableList, transform: Matrix4<f32>, material: Option<Material>) {
+        let base_vertex = self.vertices.len() as u32;
+        let first_triangle = (self.indices.len() / 3) as u32;
+        let first_texel = self.texels.len() as u32;
+        let first_node = self.blas_nodes.len() as u32;
+        let retexture = |mut material: Material| {
+            if material.texture.width > 0 {
+                material.texture.offset += first_texel;
+            }
+            material
+        };
+
+        self.vertices.extend(other.vertices);
+        self.indices.extend(other.indices.iter().map(|i| base_vertex + i));
+        self.texels.extend(other.texels);
+        let mut nodes = other.blas_nodes;
+        offset_nodes(&mut nodes, first_node, first_triangle);
+        self.blas_nodes.extend(nodes);
+        self.meshes.extend(other.meshes.iter().map(|info| MeshInfo {
+            blas_root: info.blas_root + first_node,
+            first_triangle: info.first_triangle + first_triangle,
+            material: retexture(info.material),
+            ..*info
+        }));
+
+        // The scale of a cube with the transformed one's volume
+        let linear = transform.fixed_view::<3, 3>(0, 0);
+        let sphere_scale = linear.determinant().abs().cbrt();
+        let stretched = (0..3).any(|axis| (linear.column(axis).norm() - sphere_scale).abs() > 1e-4 * sphere_scale);
+        if stretched && other.hitables.iter().any(|hitable| hitable.kind == SPHERE) {
+            warn!("Spheres can't be scaled non-uniformly, scaling them by {} on every axis", sphere_scale);
+        }
+
+        for hitable in other.hitables {
+            let hitable_material = material.unwrap_or_else(|| retexture(hitable.material));
+            match hitable.kind {
+                MESH => {
+                    let instance = &other.instances[hitable.instance as usize];
+                    let info = MeshInfo {
+                        blas_root: instance.blas_root + first_node,
+                        first_triangle: instance.first_triangle + first_triangle,
+                        triangle_count: instance.triangle_count,
+                        bounds: self.blas_nodes[(instance.blas_root + first_node) as usize].bounds(),
+                        material: hitable_material,
+                    };
+                    self.place(&info, transform * instance.object_to_world, hitable_material);
+                }
+                _ => {
+                    let center = transform.transform_point(&hitable.sphere.center.into()).coords;
+                    let sphere = Sphere::new(center, hitable.sphere.radius * sphere_scale);
+                    self.hitables.push(Hitable::new(hitable.kind, sphere, hitable_material));
+                }
+            }
+        }
+    }
+
+    fn place(&mut self, info: &MeshInfo, object_to_world: Matrix4<f32>, material: Material) {
+        let world_to_object = object_to_world
+            .try_inverse()
+            .expect("instance transforms must be invertible");
+        let bounds = info.bounds.transform(&object_to_world);
+        let sphere = Sphere::new(bounds.centroid(), (bounds.max - bounds.min).norm() / 2.0);
+        let mut hitable = Hitable::new(MESH, sphere, material);
+        hitable.instance = self.instances.len() as u32;
+        self.instances.push(Instance {
+            object_to_world,
+            world_to_object,
+            blas_root: info.blas_root,
+            first_triangle: info.first_triangle,
+            triangle_count: info.triangle_count,
+            _padding: 0,
+        });
+        self.hitables.push(hitable);
+    }
+
+    /// The object space corners of a triangle, indexed like the BLAS leaves
+    pub fn triangle(&self, triangle: u32) -> [Vector3<f32>; 3] {
+        let base = triangle as usize * 3;
+        [0, 1, 2].map(|i| self.vertices[self.indices[base + i] as usize].position)
+    }
+
+    pub fn bounds(&self, index: usize) -> Aabb {
+        let hitable = &self.hitables[index];
+        match hitable.kind {
+            MESH => {
+                let instance = &self.instances[hitable.instance as usize];
+                self.blas_nodes[instance.blas_root as usize]
+                    .bounds()
+                    .transform(&instance.object_to_world)
+            }
+            _ => hitable.sphere.bounds(),
+        }
+    }
+
+    /// CPU version of the shader's `hit_object`, returns the distance to the closest hit
+    pub fn hit(&self, index: usize, ray: &Ray, t_min: f32, t_max: f32) -> Option<f32> {
+        let hitable = &self.hitables[index];
+        match hitable.kind {
+            SPHERE => hitable.sphere.hit(ray, t_min, t_max),
+            MESH => {
+                // The direction isn't normalized, so t is the same in both spaces
+                let instance = &self.instances[hitable.instance as usize];
+                let object_ray = instance.to_object(ray);
+                let hit = traverse(&self.blas_nodes, instance.blas_root, &object_ray, t_min, t_max, |triangle, t_max| {
+                    let [p0, p1, p2] = self.triangle(triangle);
+                    intersect_triangle(&object_ray, p0, p1, p2)
+                        .map(|(t, _, _)| t)
+                        .filter(|&t| t_min < t && t < t_max)
+                });
+                hit.map(|(_, t)| t)
+            }
+            _ => None,
+        }
+    }
+
+    /// CPU version of the shader's `hit_object` that fills in the whole record
+    pub fn hit_record(&self, index: usize, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let hitable = &self.hitables[index];
+        let record = match hitable.kind {
+            SPHERE => {
+                let sphere = &hitable.sphere;
+                let t = sphere.hit(ray, t_min, t_max)?;
+                let p = ray.at(t);
+                let normal = ((p - sphere.center) / sphere.radius).normalize();
+                let uv = Vector2::new(
+                    (-normal.z).atan2(normal.x) / (2.0 * std::f32::consts::PI) + 0.5,
+                    (-normal.y).clamp(-1.0, 1.0).acos() / std::f32::consts::PI,
+                );
+                HitRecord {
+                    t,
+                    p,
+                    normal,
+                    front_face: true,
+                    material: self.apply_texture(hitable.material, uv),
+                    shape: SPHERE,
+                    size: sphere.radius,
+                    face_normal: normal,
+                }
+            }
+            MESH => {
+                let instance = &self.instances[hitable.instance as usize];
+                let object_ray = instance.to_object(ray);
+                let mut closest = None;
+                traverse(&self.blas_nodes, instance.blas_root, &object_ray, t_min, t_max, |triangle, t_max| {
+                    let [p0, p1, p2] = self.triangle(triangle);
+                    let (t, b1, b2) = intersect_triangle(&object_ray, p0, p1, p2)
+                        .filter(|&(t, _, _)| t_min < t && t < t_max)?;
+                    closest = Some((triangle, t, b1, b2));
+                    Some(t)
+                });
+                let (triangle, t, b1, b2) = closest?;
+
+                let base = triangle as usize * 3;
+                let [v0, v1, v2] = [0, 1, 2].map(|i| self.vertices[self.indices[base + i] as usize]);
+                let b0 = 1.0 - b1 - b2;
+                let edge1 = v1.position - v0.position;
+                let edge2 = v2.position - v0.position;
+                let smooth_normal = b0 * v0.normal + b1 * v1.normal + b2 * v2.normal;
+                let normal = if smooth_normal.norm_squared() > 0.0 {
+                    smooth_normal
+                } else {
+                    edge1.cross(&edge2)
+                };
+                // Normals go back to world space with the inverse transpose
+                let normal = instance.world_to_object.fixed_view::<3, 3>(0, 0).transpose() * normal;
+                let uv = b0 * Vector2::new(v0.u, v0.v) + b1 * Vector2::new(v1.u, v1.v) + b2 * Vector2::new(v2.u, v2.v);
+                let object_to_world = instance.object_to_world.fixed_view::<3, 3>(0, 0);
+                let face = (object_to_world * edge1).cross(&(object_to_world * edge2));
+                HitRecord {
+                    t,
+                    p: ray.at(t),
+                    normal: normal.normalize(),
+                    front_face: true,
+                    material: self.apply_texture(hitable.material, uv),
+                    shape: MESH,
+                    size: face.norm() / 2.0,
+                    face_normal: face.normalize(),
+                }
+            }
+            _ => return None,
+        };
+        Some(record.facing(ray))
+    }
+
+    /// The shader's `apply_texture`: bilinear with repeat wrapping, decoded
+    /// from sRGB and multiplied into the albedo
+    pub fn apply_texture(&self, material: Material, uv: Vector2<f32>) -> Material {
+        let texture = material.texture;
+        if texture.width == 0 {
+            return material;
+        }
+        let (width, height) = (texture.width as i32, texture.height as i32);
+        let load = |x: i32, y: i32| {
+            let texel = self.texels[(texture.offset + (y.rem_euclid(height) * width + x.rem_euclid(width)) as u32) as usize];
+            Vector3::from_fn(|i, _| {
+                let srgb = texel.to_le_bytes()[i] as f32 / 255.0;
+                if srgb <= 0.04045 {
+                    srgb / 12.92
+                } else {
+                    ((srgb + 0.055) / 1.055).powf(2.4)
+                }
+            })
+        };
+        let p = uv.component_mul(&Vector2::new(width as f32, height as f32)) - Vector2::repeat(0.5);
+        let base = p.map(f32::floor);
+        let f = p - base;
+        let (x, y) = (base.x as i32, base.y as i32);
+        let top = load(x, y).lerp(&load(x + 1, y), f.x);
+        let bottom = load(x, y + 1).lerp(&load(x + 1, y + 1), f.x);
+        let mut textured = material;
+        textured.albedo = material.albedo.component_mul(&top.lerp(&bottom, f.y));
+        textured
+    }
+
+    /// Adds an sRGB RGBA8 image, rows top to bottom, for use with `Material::with_texture`.
+    pub fn add_texture(&mut self, width: u32, height: u32, rgba: &[[u8; 4]]) -> Texture {
+        assert_eq!(rgba.len(), width as usize * height as usize, "texture size mismatch");
+        let texture = Texture {
+            offset: self.texels.len() as u32,
+            width,
+            height,
+        };
+        self.texels.extend(rgba.iter().map(|texel| u32::from_le_bytes(*texel)));
+        texture
+    }
+}
+
+impl From<Vec<Hitable>> for HitableList {
+    fn from(hitables: Vec<Hitable>) -> Self {
+        Self {
+            hitables,
+            ..Default::default()
+        }
+    }
+}
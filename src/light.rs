@@ -0,0 +1,163 @@
+use nalgebra::Vector3;
+use crate::hitable::*;
+
+pub const SPHERE_LIGHT: u32 = 0;
+pub const TRIANGLE_LIGHT: u32 = 1;
+
+/// An emitter the shader can sample directly, in world space.
+/// Laid out as the shader's `Light`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Light {
+    pub p0: Vector3<f32>, // sphere center, or the first corner of the triangle
+    pub kind: u32,
+    pub p1: Vector3<f32>,
+    pub radius: f32,
+    pub p2: Vector3<f32>,
+    pub area: f32,
+    pub emission: Vector3<f32>,
+    _padding: f32,
+}
+
+unsafe impl bytemuck::Pod for Light {}
+unsafe impl bytemuck::Zeroable for Light {}
+
+impl Light {
+    pub fn sphere(sphere: &Sphere, emission: Vector3<f32>) -> Self {
+        Self {
+            p0: sphere.center,
+            kind: SPHERE_LIGHT,
+            p1: Vector3::zeros(),
+            radius: sphere.radius,
+            p2: Vector3::zeros(),
+            area: 4.0 * std::f32::consts::PI * sphere.radius * sphere.radius,
+            emission,
+            _padding: 0.0,
+        }
+    }
+
+    pub fn triangle(p0: Vector3<f32>, p1: Vector3<f32>, p2: Vector3<f32>, emission: Vector3<f32>) -> Self {
+        Self {
+            p0,
+            kind: TRIANGLE_LIGHT,
+            p1,
+            radius: 0.0,
+            p2,
+            area: (p1 - p0).cross(&(p2 - p0)).norm() / 2.0,
+            emission,
+            _padding: 0.0,
+        }
+    }
+}
+
+/// Every emissive sphere and instanced triangle in the scene
+#[derive(Clone, Debug, Default)]
+pub struct LightList {
+    pub lights: Vec<Light>,
+}
+
+impl LightList {
+    pub fn build(hitable_list: &HitableList) -> Self {
+        let mut lights = Vec::new();
+        for hitable in &hitable_list.hitables {
+            if !hitable.material.is_emissive() {
+                continue;
+            }
+            let emission = hitable.material.emission;
+            match hitable.kind {
+                SPHERE if hitable.sphere.radius > 0.0 => lights.push(Light::sphere(&hitable.sphere, emission)),
+                MESH => {
+                    let instance = &hitable_list.instances[hitable.instance as usize];
+                    let m = instance.object_to_world;
+                    // Mirroring transforms flip the winding, swap corners to keep the front face
+                    let mirrored = m.fixed_view::<3, 3>(0, 0).determinant() < 0.0;
+                    let first = instance.first_triangle;
+                    for triangle in first..first + instance.triangle_count {
+                        let [p0, p1, p2] = hitable_list
+                            .triangle(triangle)
+                            .map(|p| m.transform_point(&p.into()).coords);
+                        let light = if mirrored {
+                            Light::triangle(p0, p2, p1, emission)
+                        } else {
+                            Light::triangle(p0, p1, p2, emission)
+                        };
+                        if light.area > 0.0 {
+                            lights.push(light);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Self { lights }
+    }
+
+    /// Light count followed by the lights, laid out as the shader's `LightList`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = [self.lights.len() as u32, 0, 0, 0];
+        let mut bytes = bytemuck::cast_slice(&header).to_vec();
+        if self.lights.is_empty() {
+            // Runtime sized arrays need at least one element to bind
+            bytes.extend_from_slice(bytemuck::bytes_of(&<Light as bytemuck::Zeroable>::zeroed()));
+        }
+        bytes.extend_from_slice(bytemuck::cast_slice(&self.lights));
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Matrix4;
+
+    fn quad(material: Material) -> Mesh {
+        let positions = vec![
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(1.0, 1.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        ];
+        Mesh::new(positions, vec![[0, 1, 2], [0, 2, 3]], material)
+    }
+
+    #[test]
+    fn light_layout_is_64_bytes() {
+        assert_eq!(std::mem::size_of::<Light>(), 64);
+    }
+
+    #[test]
+    fn only_emitters_become_lights() {
+        let mut list = HitableList::new();
+        let diffuse = Material::new(Vector3::new(0.5, 0.5, 0.5), LAMBERTIAN);
+        let light = Material::emissive(Vector3::new(1.0, 1.0, 1.0), 4.0);
+        list.push(Hitable::new(SPHERE, Sphere::new(Vector3::zeros(), 1.0), diffuse));
+        list.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(0.0, 5.0, 0.0), 0.5), light));
+        list.push_mesh(quad(diffuse));
+
+        let lights = LightList::build(&list).lights;
+        assert_eq!(lights.len(), 1);
+        assert_eq!(lights[0].kind, SPHERE_LIGHT);
+        assert_eq!(lights[0].radius, 0.5);
+        assert_eq!(lights[0].emission, Vector3::new(4.0, 4.0, 4.0));
+    }
+
+    #[test]
+    fn instanced_emitters_are_in_world_space() {
+        let mut list = HitableList::new();
+        let id = list.add_mesh(quad(Material::new(Vector3::new(0.5, 0.5, 0.5), LAMBERTIAN)));
+        let transform = Matrix4::new_translation(&Vector3::new(0.0, 0.0, -3.0)) * Matrix4::new_scaling(2.0);
+        list.push_instance(id, Matrix4::identity(), None);
+        list.push_instance(id, transform, Some(Material::emissive(Vector3::new(1.0, 0.5, 0.25), 1.0)));
+
+        let lights = LightList::build(&list).lights;
+        assert_eq!(lights.len(), 2);
+        let area: f32 = lights.iter().map(|light| light.area).sum();
+        assert!((area - 4.0).abs() < 1e-5);
+        for light in &lights {
+            assert_eq!(light.kind, TRIANGLE_LIGHT);
+            assert!([light.p0, light.p1, light.p2].iter().all(|p| p.z == -3.0));
+            // Winding is kept, so the front face still points along +z
+            assert!((light.p1 - light.p0).cross(&(light.p2 - light.p0)).z > 0.0);
+        }
+    }
+}
@@ -1,25 +1,173 @@
-use wgsl::ray_tracer;
-// use wgsl::hitable::*;
-// use nalgebra::Vector3;
-
-#[cfg_attr(target_arch="wasm32", wasm_bindgen(start))]
-pub fn main() {
-
-    // let sphere1 = Sphere::new(Vector3::new(0.0, 0.0, -1.2), 0.5);
-    // let material1 = Material::new(Vector3::new(0.8, 0.3, 0.3), 0);
-    // let sphere2: Sphere = Sphere::new(Vector3::new(0.0, -100.5, -1.0), 100.0);
-    // let material2 = Material::new(Vector3::new(0.8, 0.8, 0.0), 0);
-    // let sphere3: Sphere = Sphere::new(Vector3::new(-1.0, 0.0, -1.0), 0.5);
-    // let material3 = Material::new(Vector3::new(0.8, 0.6, 0.2), 1);
-    // let sphere4: Sphere = Sphere::new(Vector3::new(1.0, 0.0, -1.0), 0.5);
-    // let material4 = Material::new(Vector3::new(0.8, 0.8, 0.8), 1);
-    
-    // let hitable1 = Hitable::new(0, sphere1, material1);
-    // let hitable2 = Hitable::new(0, sphere2, material2);
-    // let hitable3 = Hitable::new(0, sphere3, material3);
-    // let hitable4 = Hitable::new(0, sphere4, material4);
-
-    // let hitable_list = vec![hitable1, hitable2, hitable3, hitable4];
-
-    ray_tracer();
-}
\ No newline at end of file
+#[cfg(not(target_arch = "wasm32"))]
+mod cli {
+    use std::path::PathBuf;
+    use clap::{Args, Parser, Subcommand};
+    use wgsl::environment::Environment;
+    use wgsl::headless::render_to_png;
+    use wgsl::reference::ReferenceRenderer;
+    use wgsl::sampler::Sampler;
+    use wgsl::{default_scene, ray_tracer_with, AdapterPreference, Integrator, Scene};
+
+    /// Progressive GPU path tracer. Opens the interactive viewer unless a
+    /// subcommand says otherwise.
+    #[derive(Parser)]
+    #[command(version, about, args_conflicts_with_subcommands = true)]
+    struct Cli {
+        #[command(subcommand)]
+        command: Option<Command>,
+        #[command(flatten)]
+        scene: SceneArgs,
+    }
+
+    #[derive(Subcommand)]
+    enum Command {
+        /// Open the scene in a window, move around with WASD/QE and look with IJKL/UO
+        View(SceneArgs),
+        /// Render the scene offscreen and write it out as a PNG
+        Render {
+            #[command(flatten)]
+            scene: SceneArgs,
+            /// Where to write the image
+            #[arg(short, long, default_value = "out.png")]
+            output: PathBuf,
+            /// Trace on the CPU with the reference renderer, no GPU needed
+            #[arg(long, conflicts_with_all = ["adapter", "integrator"])]
+            cpu: bool,
+        },
+    }
+
+    /// Overrides for the scene file's own settings
+    #[derive(Args)]
+    struct SceneArgs {
+        /// Scene description (.toml), glTF (.gltf, .glb) or OBJ file, the
+        /// built in spheres when missing
+        scene: Option<PathBuf>,
+        /// Image width in pixels, also the window's initial width
+        #[arg(long)]
+        width: Option<u32>,
+        /// Image height in pixels, also the window's initial height
+        #[arg(long)]
+        height: Option<u32>,
+        /// Samples per pixel traced in each iteration
+        #[arg(short, long)]
+        samples_per_pixel: Option<u32>,
+        /// Bounces before a path is cut off
+        #[arg(short = 'd', long)]
+        max_depth: Option<u32>,
+        /// Iterations to accumulate before stopping
+        #[arg(short = 'n', long)]
+        iterations: Option<u32>,
+        /// Where sample positions come from: random, sobol or blue-noise
+        #[arg(long)]
+        sampler: Option<Sampler>,
+        /// Equirectangular HDR or EXR map lighting the scene
+        #[arg(short, long)]
+        environment: Option<PathBuf>,
+        /// Turns the environment map about the up axis, in degrees
+        #[arg(long, default_value_t = 0.0, requires = "environment")]
+        environment_rotation: f32,
+        /// Scales the environment map's radiance
+        #[arg(long, default_value_t = 1.0, requires = "environment")]
+        environment_intensity: f32,
+        /// GPU to render on: high-performance, low-power or fallback (software)
+        #[arg(short, long, default_value = "high-performance")]
+        adapter: AdapterPreference,
+        /// Trace with compute shaders or with the downlevel (WebGL2) render
+        /// passes, picked from what the adapter supports when missing
+        #[arg(short, long)]
+        integrator: Option<Integrator>,
+    }
+
+    impl SceneArgs {
+        fn load(&self) -> Result<Scene, String> {
+            let mut scene = match &self.scene {
+                Some(path) => Scene::load(path).map_err(|err| format!("Failed to load {}: {}", path.display(), err))?,
+                None => default_scene(),
+            };
+            let settings = &mut scene.settings;
+            settings.width = self.width.unwrap_or(settings.width);
+            settings.height = self.height.unwrap_or(settings.height);
+            settings.samples_per_pixel = self.samples_per_pixel.unwrap_or(settings.samples_per_pixel);
+            settings.max_depth = self.max_depth.unwrap_or(settings.max_depth);
+            settings.iterations = self.iterations.unwrap_or(settings.iterations);
+            settings.sampler = self.sampler.unwrap_or(settings.sampler);
+            if let Some(path) = &self.environment {
+                let environment = Environment::load(path)
+                    .map_err(|err| format!("Failed to load {}: {}", path.display(), err))?
+                    .with_rotation(self.environment_rotation)
+                    .with_intensity(self.environment_intensity);
+                scene = scene.with_environment(environment);
+            }
+            Ok(scene)
+        }
+    }
+
+    // Bad paths are reported, not panicked over: release builds abort on panics
+    pub fn main() {
+        if let Err(message) = run(Cli::parse()) {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+
+    fn run(cli: Cli) -> Result<(), String> {
+        match cli.command {
+            None => ray_tracer_with(cli.scene.load()?, cli.scene.adapter, cli.scene.integrator),
+            Some(Command::View(args)) => ray_tracer_with(args.load()?, args.adapter, args.integrator),
+            Some(Command::Render { scene: args, output, cpu }) => {
+                env_logger::init();
+                let scene = args.load()?;
+                let result = if cpu {
+                    ReferenceRenderer::new(scene).render_image().save_with_format(&output, image::ImageFormat::Png)
+                } else {
+                    render_to_png(scene, args.adapter, args.integrator, &output)
+                };
+                result.map_err(|err| format!("Failed to write {}: {}", output.display(), err))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use clap::CommandFactory;
+
+        #[test]
+        fn arguments_are_consistent() {
+            Cli::command().debug_assert();
+        }
+
+        #[test]
+        fn render_takes_overrides_and_an_adapter() {
+            let cli = Cli::try_parse_from(["wgsl", "render", "scene.toml", "-n", "8", "-a", "fallback", "-i", "downlevel", "-o", "x.png"]).unwrap();
+            let Some(Command::Render { scene, output, cpu }) = cli.command else {
+                panic!("expected the render subcommand");
+            };
+            assert_eq!(scene.scene, Some(PathBuf::from("scene.toml")));
+            assert_eq!(scene.iterations, Some(8));
+            assert_eq!(scene.adapter, AdapterPreference::Fallback);
+            assert_eq!(scene.integrator, Some(Integrator::Downlevel));
+            assert_eq!(output, PathBuf::from("x.png"));
+            assert!(!cpu);
+            assert!(Cli::try_parse_from(["wgsl", "render", "--cpu", "-i", "compute"]).is_err());
+            assert!(Cli::try_parse_from(["wgsl", "--adapter", "integrated"]).is_err());
+        }
+
+        #[test]
+        fn missing_scenes_are_reported() {
+            let cli = Cli::try_parse_from(["wgsl", "render", "--cpu", "no/such/scene.toml"]).unwrap();
+            let message = run(cli).unwrap_err();
+            assert!(message.starts_with("Failed to load no/such/scene.toml"), "{}", message);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    cli::main();
+}
+
+// The library's `ray_tracer` is the entry point on the web
+#[cfg(target_arch = "wasm32")]
+fn main() {}
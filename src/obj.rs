@@ -0,0 +1,176 @@
+use std::path::Path;
+use log::*;
+use nalgebra::Vector3;
+use crate::hitable::*;
+
+/// Loads a Wavefront OBJ file (and the MTL libraries it references) as one
+/// mesh per OBJ model. Faces are triangulated on load.
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<HitableList, tobj::LoadError> {
+    let path = path.as_ref();
+    info!("Loading {:?}", path);
+    let (models, materials) = tobj::load_obj(path, &tobj::LoadOptions {
+        triangulate: true,
+        single_index: true,
+        ..Default::default()
+    })?;
+    let materials = materials.unwrap_or_else(|err| {
+        warn!("Failed to load materials for {:?}: {}", path, err);
+        Vec::new()
+    });
+
+    let mut hitable_list = HitableList::new();
+    for model in models {
+        let mesh = model.mesh;
+        if mesh.indices.is_empty() {
+            continue;
+        }
+        let material = match mesh.material_id.and_then(|id| materials.get(id)) {
+            Some(mtl) => convert_material(mtl),
+            None => default_material(),
+        };
+        let positions = mesh.positions
+            .chunks_exact(3)
+            .map(|p| Vector3::new(p[0], p[1], p[2]))
+            .collect();
+        let indices = mesh.indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect();
+        let mut converted = Mesh::new(positions, indices, material);
+        if !mesh.normals.is_empty() {
+            let normals = mesh.normals
+                .chunks_exact(3)
+                .map(|n| Vector3::new(n[0], n[1], n[2]))
+                .collect();
+            converted = converted.with_normals(normals);
+        }
+        info!("Loaded model {:?} with {} triangles", model.name, converted.indices.len());
+        hitable_list.push_mesh(converted);
+    }
+    Ok(hitable_list)
+}
+
+fn default_material() -> Material {
+    Material::new(Vector3::new(0.8, 0.8, 0.8), LAMBERTIAN)
+}
+
+/// Maps an MTL material onto the closest material kind the shader supports.
+/// Illumination models 3 and 5 (ray traced reflection) become metals tinted by
+/// `Ks` with roughness derived from `Ns`, models 4, 6 and 7 (refraction) become
+/// dielectrics with index `Ni` tinted by `Tf`, everything else is treated as
+/// diffuse `Kd`. `Ke` makes any of them emissive.
+fn convert_material(mtl: &tobj::Material) -> Material {
+    let diffuse = mtl.diffuse.map(Vector3::from).unwrap_or(Vector3::new(0.8, 0.8, 0.8));
+    let specular = mtl.specular.map(Vector3::from);
+    let material = convert_kind(mtl, diffuse, specular);
+    match unknown_vector(mtl, "Ke") {
+        Some(emission) => material.with_emission(emission, 1.0),
+        None => material,
+    }
+}
+
+fn convert_kind(mtl: &tobj::Material, diffuse: Vector3<f32>, specular: Option<Vector3<f32>>) -> Material {
+    match mtl.illumination_model {
+        Some(3) | Some(5) => {
+            // Common Blinn-Phong exponent to roughness conversion
+            let roughness = mtl.shininess.map_or(0.0, |ns| (2.0 / (ns.max(0.0) + 2.0)).sqrt());
+            Material::new(specular.unwrap_or(diffuse), METAL).with_roughness(roughness)
+        }
+        Some(4) | Some(6) | Some(7) => {
+            let transmission = unknown_vector(mtl, "Tf").unwrap_or(Vector3::new(1.0, 1.0, 1.0));
+            Material::new(transmission, DIELECTRIC).with_ior(mtl.optical_density.unwrap_or(1.5))
+        }
+        _ => Material::new(diffuse, LAMBERTIAN),
+    }
+}
+
+/// Parses an `r g b` statement that tobj doesn't know about
+fn unknown_vector(mtl: &tobj::Material, key: &str) -> Option<Vector3<f32>> {
+    let values: Vec<f32> = mtl.unknown_param
+        .get(key)?
+        .split_whitespace()
+        .map(|v| v.parse().ok())
+        .collect::<Option<_>>()?;
+    match values[..] {
+        [v] => Some(Vector3::new(v, v, v)),
+        [r, g, b] => Some(Vector3::new(r, g, b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MTL: &str = "\
+newmtl clay
+Kd 0.6 0.5 0.4
+
+newmtl steel
+illum 3
+Kd 0.1 0.1 0.1
+Ks 0.9 0.8 0.7
+Ns 98
+
+newmtl mirror
+illum 5
+Ks 1 1 1
+
+newmtl glass
+illum 7
+Ni 1.33
+Tf 0.9 1 0.9
+
+newmtl lamp
+Kd 0.2 0.2 0.2
+Ke 4 3 2
+";
+
+    /// One copy of the quad per material in `MTL`, loaded from a directory of
+    /// the calling test's own, as tests run in parallel
+    fn load_fixture(test: &str, names: &[&str]) -> HitableList {
+        let quad = include_str!("../scenes/quad.obj");
+        let mut obj = String::from("mtllib quad.mtl\n");
+        quad.lines().filter(|l| l.starts_with("v ")).for_each(|l| obj += &format!("{}\n", l));
+        for name in names {
+            obj += &format!("o {}\nusemtl {}\n", name, name);
+            quad.lines().filter(|l| l.starts_with("f ")).for_each(|l| obj += &format!("{}\n", l));
+        }
+        let directory = std::env::temp_dir().join(format!("wgsl-obj-{}-{}", std::process::id(), test));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("quad.mtl"), MTL).unwrap();
+        std::fs::write(directory.join("quad.obj"), obj).unwrap();
+        let hitable_list = load_obj(directory.join("quad.obj")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        hitable_list
+    }
+
+    #[test]
+    fn mtl_statements_map_onto_materials() {
+        let names = ["clay", "steel", "mirror", "glass", "lamp"];
+        let hitable_list = load_fixture("mtl_statements_map_onto_materials", &names);
+        assert_eq!(hitable_list.hitables.len(), names.len());
+        assert_eq!(hitable_list.indices.len(), names.len() * 2 * 3);
+        let [clay, steel, mirror, glass, lamp] = [0, 1, 2, 3, 4].map(|i| hitable_list.hitables[i].material);
+
+        assert_eq!(clay.kind(), LAMBERTIAN);
+        assert_eq!(clay.albedo(), Vector3::new(0.6, 0.5, 0.4));
+        assert!(!clay.is_emissive());
+
+        // illum 3 is tinted by Ks rather than Kd, Ns 98 gives sqrt(2 / 100)
+        assert_eq!(steel.kind(), METAL);
+        assert_eq!(steel.albedo(), Vector3::new(0.9, 0.8, 0.7));
+        assert!((steel.roughness() - 0.02_f32.sqrt()).abs() < 1e-6);
+        assert_eq!(mirror.kind(), METAL);
+        assert_eq!(mirror.roughness(), 0.0);
+
+        assert_eq!(glass.kind(), DIELECTRIC);
+        assert_eq!(glass.ior(), 1.33);
+        assert_eq!(glass.albedo(), Vector3::new(0.9, 1.0, 0.9));
+
+        // Ke adds emission without changing the kind
+        assert_eq!(lamp.kind(), LAMBERTIAN);
+        assert_eq!(lamp.albedo(), Vector3::new(0.2, 0.2, 0.2));
+        assert_eq!(lamp.emission, Vector3::new(4.0, 3.0, 2.0));
+    }
+}
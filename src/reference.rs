@@ -0,0 +1,577 @@
+use std::f32::consts::PI;
+use std::sync::Mutex;
+use nalgebra::{Matrix3, Vector3};
+use crate::bvh::{traverse, Bvh};
+use crate::camera::Camera;
+use crate::environment::{Environment, EnvironmentDistribution, EnvironmentSettings};
+use crate::hitable::*;
+use crate::light::{Light, LightList, SPHERE_LIGHT};
+use crate::rng::{unit_float, Pcg32};
+use crate::sampler::{blue_noise_2d, scrambled_sobol_2d, sobol_direction_numbers, Sampler, SOBOL_DIMENSIONS};
+use crate::sampling::{cosine_direction, cosine_pdf, onb, unit_vector};
+use crate::scene::Scene;
+
+// The shader's ray offset and farthest hit
+const T_MIN: f32 = 0.001;
+const MAX_F32: f32 = 1_000_000.0;
+
+// Draws a bounce may take, as in the shader
+const DIMENSIONS_PER_BOUNCE: u32 = 3;
+
+/// Path traces a scene on the CPU with the shader's integrator: the same
+/// camera rays, samplers, light sampling and materials, so its images
+/// converge to what the GPU renders. Slow, but needs no adapter at all.
+pub struct ReferenceRenderer {
+    hitable_list: HitableList,
+    bvh: Option<Bvh>, // None when there's nothing to hit
+    lights: Vec<Light>,
+    environment: Option<(Environment, EnvironmentDistribution)>,
+    camera: Camera,
+    width: u32,
+    height: u32,
+    iterations: u32,
+    sampler: Sampler,
+    sobol_directions: Vec<u32>,
+    threads: usize,
+}
+
+impl ReferenceRenderer {
+    /// Sized and sampled like a headless `Renderer` from the scene's settings
+    pub fn new(scene: Scene) -> Self {
+        let Scene { mut hitable_list, camera, environment, settings } = scene;
+        let lights = LightList::build(&hitable_list).lights;
+        let bvh = if hitable_list.hitables.is_empty() {
+            None
+        } else {
+            Some(Bvh::build(&mut hitable_list))
+        };
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        Self {
+            hitable_list,
+            bvh,
+            lights,
+            environment: environment.map(|environment| {
+                let distribution = environment.distribution();
+                (environment, distribution)
+            }),
+            camera: Camera::new(settings.width, settings.height as f32, &camera, &settings),
+            width: settings.width,
+            height: settings.height,
+            iterations: settings.iterations.max(1),
+            sampler: settings.sampler,
+            sobol_directions: sobol_direction_numbers(SOBOL_DIMENSIONS),
+            threads,
+        }
+    }
+
+    /// Rows are handed out to this many threads, every core by default
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Linear radiance averaged over every sample of every iteration. The
+    /// numbers drawn depend only on the pixel and sample, not on the threads.
+    pub fn render(&self) -> image::Rgba32FImage {
+        let row_length = self.width as usize * 4;
+        let mut pixels = vec![0.0; row_length * self.height as usize];
+        let rows = Mutex::new(pixels.chunks_mut(row_length).enumerate());
+        std::thread::scope(|scope| {
+            for _ in 0..self.threads {
+                scope.spawn(|| loop {
+                    let next = rows.lock().unwrap().next();
+                    let Some((y, row)) = next else {
+                        break;
+                    };
+                    for (x, pixel) in row.chunks_mut(4).enumerate() {
+                        let color = self.trace_pixel(x as u32, y as u32);
+                        pixel.copy_from_slice(&[color.x, color.y, color.z, 1.0]);
+                    }
+                });
+            }
+        });
+        image::Rgba32FImage::from_raw(self.width, self.height, pixels).unwrap()
+    }
+
+    /// `render` clipped and sRGB encoded, as `Renderer::read_image` returns it
+    pub fn render_image(&self) -> image::RgbaImage {
+        let image = self.render();
+        image::RgbaImage::from_fn(self.width, self.height, |x, y| {
+            image::Rgba(image.get_pixel(x, y).0.map(|c| (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0).round() as u8))
+        })
+    }
+
+    /// The shader's `trace_pixel` over all the iterations at once
+    pub fn trace_pixel(&self, x: u32, y: u32) -> Vector3<f32> {
+        let camera = &self.camera;
+        let rotation: Matrix3<f32> = camera.rotation.fixed_view::<3, 3>(0, 0).into();
+        let samples = self.iterations * camera.samples_per_pixel;
+        let mut color = Vector3::zeros();
+        for sample in 0..samples {
+            let mut sampler = PixelSampler::start(self, [x, y], sample);
+            // Offset from the pixel center, within the pixel
+            let jitter = sampler.sample_2d().map(|u| u - 0.5);
+            let pixel_loc = camera.pixel00_loc
+                + (x as f32 + 0.5 + jitter[0]) * camera.pixel_delta_u
+                + (y as f32 + 0.5 + jitter[1]) * camera.pixel_delta_v;
+            let ray = Ray::new(camera.center, rotation * (pixel_loc - camera.center));
+            color += self.ray_color(ray, &mut sampler);
+        }
+        color / samples as f32
+    }
+
+    fn ray_color(&self, ray: Ray, sampler: &mut PixelSampler) -> Vector3<f32> {
+        let mut radiance = Vector3::zeros();
+        let mut throughput = Vector3::repeat(1.0);
+        let mut ray = ray;
+        // Pdf of the bounce that produced ray, zero when light sampling couldn't have
+        let mut bsdf_pdf = 0.0;
+        for depth in 0..self.camera.max_depth {
+            let Some(record) = self.hit(&ray, T_MIN, MAX_F32) else {
+                let mut weight = 1.0;
+                if bsdf_pdf > 0.0 && self.environment.is_some() {
+                    let environment_pdf = self.environment_pdf(&ray.direction.normalize());
+                    weight = power_heuristic(bsdf_pdf, environment_pdf / self.light_choices() as f32);
+                }
+                radiance += throughput.component_mul(&self.environment_color(&ray.direction)) * weight;
+                break;
+            };
+            // Emitters shine from their geometric front, the side sample_light sees
+            if ray.direction.dot(&record.face_normal) < 0.0 {
+                let mut weight = 1.0;
+                if bsdf_pdf > 0.0 && record.material.is_emissive() {
+                    weight = power_heuristic(bsdf_pdf, self.light_pdf(&record, &ray));
+                }
+                radiance += throughput.component_mul(&record.material.emission) * weight;
+            }
+            sampler.bounce(depth + 1);
+            if record.material.kind() == LAMBERTIAN {
+                radiance += throughput.component_mul(&self.sample_light(&record, sampler));
+            }
+            let Some((attenuation, scattered, pdf)) = scatter(&record, &ray, sampler) else {
+                // Absorbed, or a light that doesn't reflect
+                break;
+            };
+            throughput.component_mul_assign(&attenuation);
+            bsdf_pdf = pdf;
+            ray = scattered;
+        }
+        radiance
+    }
+
+    // The shader's `get_hit_record`
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let bvh = self.bvh.as_ref()?;
+        let mut closest = None;
+        traverse(&bvh.nodes, 0, ray, t_min, t_max, |i, t_max| {
+            let record = self.hitable_list.hit_record(i as usize, ray, t_min, t_max)?;
+            closest = Some(record);
+            Some(record.t)
+        });
+        closest
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f32) -> bool {
+        self.bvh
+            .as_ref()
+            .is_some_and(|bvh| bvh.hit(&self.hitable_list, ray, T_MIN, t_max).is_some())
+    }
+
+    fn light_choices(&self) -> u32 {
+        self.lights.len() as u32 + self.environment.is_some() as u32
+    }
+
+    // Next event estimation as in the shader's `sample_light`
+    fn sample_light(&self, record: &HitRecord, sampler: &mut PixelSampler) -> Vector3<f32> {
+        let choices = self.light_choices();
+        if choices == 0 {
+            return Vector3::zeros();
+        }
+        let count = choices as f32;
+        let index = ((sampler.sample_1d() * count) as u32).min(choices - 1) as usize;
+        let u = sampler.sample_2d();
+
+        let (direction, distance, pdf, emission) = if index == self.lights.len() {
+            let Some((direction, pdf)) = self.sample_environment_direction(u) else {
+                return Vector3::zeros();
+            };
+            (direction, MAX_F32, pdf, self.environment_color(&direction))
+        } else if self.lights[index].kind == SPHERE_LIGHT {
+            let light = &self.lights[index];
+            // Uniform over the cone of directions the sphere covers
+            let to_center = light.p0 - record.p;
+            let distance_squared = to_center.norm_squared();
+            let sin2_max = light.radius * light.radius / distance_squared;
+            if sin2_max >= 1.0 {
+                return Vector3::zeros();
+            }
+            let one_minus_cos_max = sin2_max / (1.0 + (1.0 - sin2_max).sqrt());
+            let cos_theta = 1.0 - u[0] * one_minus_cos_max;
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * u[1];
+            let direction = onb(&(to_center / distance_squared.sqrt()))
+                * Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+            let b = to_center.dot(&direction);
+            let distance = b - (light.radius * light.radius - (distance_squared - b * b)).max(0.0).sqrt();
+            (direction, distance, 1.0 / (2.0 * PI * one_minus_cos_max), light.emission)
+        } else {
+            // Uniform over the triangle's area
+            let light = &self.lights[index];
+            let su = u[0].sqrt();
+            let point = (1.0 - su) * light.p0 + su * (1.0 - u[1]) * light.p1 + su * u[1] * light.p2;
+            let to_light = point - record.p;
+            let distance_squared = to_light.norm_squared();
+            let distance = distance_squared.sqrt();
+            let direction = to_light / distance;
+            let cos_light = -(light.p1 - light.p0).cross(&(light.p2 - light.p0)).normalize().dot(&direction);
+            if cos_light <= 0.0 {
+                return Vector3::zeros();
+            }
+            (direction, distance, distance_squared / (cos_light * light.area), light.emission)
+        };
+
+        let cos_surface = record.normal.dot(&direction);
+        if cos_surface <= 0.0 {
+            return Vector3::zeros();
+        }
+        if self.occluded(&Ray::new(record.p, direction), distance * 0.999) {
+            return Vector3::zeros();
+        }
+        let light_pdf = pdf / count;
+        let brdf = record.material.albedo() / PI;
+        let weight = power_heuristic(light_pdf, cos_surface / PI);
+        brdf.component_mul(&emission) * cos_surface * weight / light_pdf
+    }
+
+    // Solid angle pdf of sample_light choosing the point the ray hit on an emitter
+    fn light_pdf(&self, record: &HitRecord, ray: &Ray) -> f32 {
+        let count = self.light_choices() as f32;
+        if record.shape == SPHERE {
+            // Front faces have outward normals
+            let to_center = record.p - record.size * record.normal - ray.origin;
+            let sin2_max = record.size * record.size / to_center.norm_squared();
+            if sin2_max >= 1.0 {
+                return 0.0;
+            }
+            let one_minus_cos_max = sin2_max / (1.0 + (1.0 - sin2_max).sqrt());
+            return 1.0 / (2.0 * PI * one_minus_cos_max * count);
+        }
+        // Same density as sample_light, so the triangle's normal, not the shading one
+        let distance = record.t * ray.direction.norm();
+        let cos_light = record.face_normal.dot(&ray.direction.normalize()).abs();
+        distance * distance / (cos_light * record.size * count)
+    }
+
+    fn environment_settings(&self) -> Option<EnvironmentSettings> {
+        self.environment.as_ref().map(|(environment, _)| environment.settings())
+    }
+
+    fn environment_color(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let direction = direction.normalize();
+        let Some((environment, _)) = &self.environment else {
+            let a = 0.5 * (direction.y + 1.0);
+            return (1.0 - a) * Vector3::repeat(1.0) + a * Vector3::new(0.5, 0.7, 1.0);
+        };
+        // Bilinear, wrapping around horizontally and clamped at the poles
+        let [u, v] = self.environment_uv(&direction);
+        let (width, height) = (environment.width as i32, environment.height as i32);
+        let (px, py) = (u * width as f32 - 0.5, v * height as f32 - 0.5);
+        let (fx, fy) = (px - px.floor(), py - py.floor());
+        let x0 = (px.floor() as i32).rem_euclid(width);
+        let x1 = (x0 + 1) % width;
+        let y0 = (py.floor() as i32).clamp(0, height - 1);
+        let y1 = (py.floor() as i32 + 1).clamp(0, height - 1);
+        let load = |x: i32, y: i32| Vector3::from_row_slice(&environment.pixels[(y * width + x) as usize][..3]);
+        let top = load(x0, y0).lerp(&load(x1, y0), fx);
+        let bottom = load(x0, y1).lerp(&load(x1, y1), fx);
+        environment.intensity * top.lerp(&bottom, fy)
+    }
+
+    // Equirectangular coordinates of a world space direction, v = 0 looks up
+    fn environment_uv(&self, direction: &Vector3<f32>) -> [f32; 2] {
+        let rotation = self.environment_settings().map_or(0.0, |settings| settings.rotation);
+        let (s, c) = rotation.sin_cos();
+        let d = Vector3::new(c * direction.x - s * direction.z, direction.y, s * direction.x + c * direction.z);
+        [d.x.atan2(-d.z) / (2.0 * PI) + 0.5, d.y.clamp(-1.0, 1.0).acos() / PI]
+    }
+
+    fn environment_direction(&self, uv: [f32; 2]) -> Vector3<f32> {
+        let rotation = self.environment_settings().map_or(0.0, |settings| settings.rotation);
+        let (s, c) = rotation.sin_cos();
+        let (theta, phi) = (uv[1] * PI, (uv[0] - 0.5) * 2.0 * PI);
+        let d = Vector3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos());
+        Vector3::new(c * d.x + s * d.z, d.y, -s * d.x + c * d.z)
+    }
+
+    // The shader's `sample_environment_direction`, a direction and its solid angle pdf
+    fn sample_environment_direction(&self, u: [f32; 2]) -> Option<(Vector3<f32>, f32)> {
+        let (_, distribution) = self.environment.as_ref()?;
+        let (x, y) = distribution.sample(u[0], u[1]);
+        let width = distribution.width as usize;
+        let row = &distribution.conditional[y as usize * width..][..width];
+        let (y_before, y_after) = cdf_step(&distribution.marginal, y as usize);
+        let (x_before, x_after) = cdf_step(row, x as usize);
+        // Where u falls inside the step places the sample inside the pixel
+        let dy = ((u[0] - y_before) / (y_after - y_before).max(1e-12)).clamp(0.0, 1.0);
+        let dx = ((u[1] - x_before) / (x_after - x_before).max(1e-12)).clamp(0.0, 1.0);
+        let uv = [(x as f32 + dx) / distribution.width as f32, (y as f32 + dy) / distribution.height as f32];
+        let sin_theta = (uv[1] * PI).sin();
+        if sin_theta <= 0.0 {
+            return None;
+        }
+        let pdf = distribution.pdf(x, y) / (2.0 * PI * PI * sin_theta);
+        Some((self.environment_direction(uv), pdf))
+    }
+
+    fn environment_pdf(&self, direction: &Vector3<f32>) -> f32 {
+        let Some((_, distribution)) = &self.environment else {
+            return 0.0;
+        };
+        let uv = self.environment_uv(direction);
+        let sin_theta = (uv[1] * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let (width, height) = (distribution.width as i32, distribution.height as i32);
+        let x = ((uv[0] * width as f32) as i32).clamp(0, width - 1);
+        let y = ((uv[1] * height as f32) as i32).clamp(0, height - 1);
+        distribution.pdf(x as u32, y as u32) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+// The shader's `scatter`: attenuation, the next ray and its solid angle pdf,
+// zero for specular bounces. None when the path ends.
+fn scatter(record: &HitRecord, ray: &Ray, sampler: &mut PixelSampler) -> Option<(Vector3<f32>, Ray, f32)> {
+    let material = &record.material;
+    match material.kind() {
+        LAMBERTIAN => {
+            let direction = cosine_direction(&record.normal, sampler.sample_2d());
+            let pdf = cosine_pdf(&record.normal, &direction);
+            (pdf > 0.0).then(|| (material.albedo(), Ray::new(record.p, direction), pdf))
+        }
+        METAL => {
+            let reflected = reflect(&ray.direction.normalize(), &record.normal)
+                + material.roughness() * unit_vector(sampler.sample_2d());
+            // Fuzzed reflections that end up below the surface are absorbed
+            (reflected.dot(&record.normal) > 0.0).then(|| (material.albedo(), Ray::new(record.p, reflected), 0.0))
+        }
+        DIELECTRIC => {
+            let ri = if record.front_face { 1.0 / material.ior() } else { material.ior() };
+            let unit_direction = ray.direction.normalize();
+            let cos_theta = (-unit_direction).dot(&record.normal).min(1.0);
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let cannot_refract = ri * sin_theta > 1.0;
+            let direction = if cannot_refract || reflectance(cos_theta, ri) > sampler.sample_1d() {
+                reflect(&unit_direction, &record.normal)
+            } else {
+                refract(&unit_direction, &record.normal, ri)
+            };
+            Some((material.albedo(), Ray::new(record.p, direction), 0.0))
+        }
+        // EMISSIVE only emits
+        _ => None,
+    }
+}
+
+fn reflect(v: &Vector3<f32>, n: &Vector3<f32>) -> Vector3<f32> {
+    v - 2.0 * v.dot(n) * n
+}
+
+// WGSL's `refract`
+fn refract(v: &Vector3<f32>, n: &Vector3<f32>, eta: f32) -> Vector3<f32> {
+    let cos = n.dot(v);
+    let k = 1.0 - eta * eta * (1.0 - cos * cos);
+    if k < 0.0 {
+        return Vector3::zeros();
+    }
+    eta * v - (eta * cos + k.sqrt()) * n
+}
+
+fn reflectance(cosine: f32, refraction_index: f32) -> f32 {
+    let r0 = ((1.0 - refraction_index) / (1.0 + refraction_index)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    a / (a + other_pdf * other_pdf)
+}
+
+// The CDF before and after step i
+fn cdf_step(cdf: &[f32], i: usize) -> (f32, f32) {
+    (if i == 0 { 0.0 } else { cdf[i - 1] }, cdf[i])
+}
+
+/// The sRGB transfer function, as sRGB render targets encode on write
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// The shader's sampler state for one sample of a pixel, see `sampler_start`
+/// and `sampler_bounce`
+struct PixelSampler<'a> {
+    sampler: Sampler,
+    sobol_directions: &'a [u32],
+    pixel: [u32; 2],
+    pixel_index: u32,
+    sample: u32,
+    dimension: u32,
+    rng: Pcg32,
+}
+
+impl<'a> PixelSampler<'a> {
+    fn start(renderer: &'a ReferenceRenderer, pixel: [u32; 2], sample: u32) -> Self {
+        let pixel_index = pixel[1] * renderer.width + pixel[0];
+        Self {
+            sampler: renderer.sampler,
+            sobol_directions: &renderer.sobol_directions,
+            pixel,
+            pixel_index,
+            sample,
+            dimension: 0,
+            rng: Pcg32::new(pixel_index, sample, 0),
+        }
+    }
+
+    fn bounce(&mut self, bounce: u32) {
+        self.dimension = bounce * DIMENSIONS_PER_BOUNCE;
+        self.rng = Pcg32::new(self.pixel_index, self.sample, bounce);
+    }
+
+    fn sample_1d(&mut self) -> f32 {
+        self.sample_2d()[0]
+    }
+
+    fn sample_2d(&mut self) -> [f32; 2] {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        match self.sampler {
+            Sampler::Random => [self.rng.next_f32(), self.rng.next_f32()],
+            Sampler::Sobol => scrambled_sobol_2d(self.sobol_directions, self.pixel_index, self.sample, dimension).map(unit_float),
+            Sampler::BlueNoise => blue_noise_2d(self.pixel, self.sample, dimension).map(unit_float),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::{RenderSettings, SceneCamera};
+
+    fn settings(width: u32, height: u32, samples_per_pixel: u32) -> RenderSettings {
+        RenderSettings {
+            width,
+            height,
+            samples_per_pixel,
+            max_depth: 8,
+            iterations: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn threads_render_the_same_image() {
+        let mut list = HitableList::new();
+        list.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(0.0, 0.0, -2.0), 0.5), Material::dielectric(1.5)));
+        list.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(0.8, 0.0, -2.5), 0.5), Material::new(Vector3::new(0.8, 0.3, 0.3), LAMBERTIAN)));
+        list.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(-0.8, 1.0, -2.0), 0.3), Material::emissive(Vector3::new(1.0, 1.0, 1.0), 4.0)));
+        for sampler in [Sampler::Random, Sampler::Sobol, Sampler::BlueNoise] {
+            let scene = Scene::new(list.clone()).with_settings(RenderSettings { sampler, ..settings(12, 9, 2) });
+            let one = ReferenceRenderer::new(scene.clone()).with_threads(1).render();
+            let many = ReferenceRenderer::new(scene).with_threads(5).render();
+            assert_eq!(one, many, "{:?} sampler", sampler);
+        }
+    }
+
+    #[test]
+    fn diffuse_sphere_in_a_white_furnace() {
+        // A convex object can't see itself, so under radiance 1 from every
+        // direction it reflects exactly its albedo. Light sampling the map
+        // and scattering into it both count, weighted by MIS.
+        let albedo = 0.6;
+        let mut list = HitableList::new();
+        list.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(0.0, 0.0, -3.0), 1.0), Material::new(Vector3::repeat(albedo), LAMBERTIAN)));
+        let environment = Environment::new(8, 4, vec![[1.0, 1.0, 1.0, 1.0]; 32]).with_rotation(30.0);
+        let scene = Scene::new(list)
+            .with_environment(environment)
+            .with_settings(settings(5, 5, 256))
+            .with_camera(SceneCamera { vfov: 10.0, ..Default::default() });
+        let image = ReferenceRenderer::new(scene).render();
+        assert!(image.pixels().all(|pixel| pixel.0[0] == pixel.0[1] && pixel.0[1] == pixel.0[2]));
+        // Single pixels are still noisy, the 6400 samples together are not
+        let mean = image.pixels().map(|pixel| pixel.0[0]).sum::<f32>() / 25.0;
+        assert!((mean - albedo).abs() < 0.015, "furnace radiance {}", mean);
+    }
+
+    #[test]
+    fn sphere_light_over_a_diffuse_mesh() {
+        // A sphere light of radius r and radiance L at distance d, fully above
+        // the horizon, gives the irradiance pi L (r / d)^2 cos(theta). The
+        // plane reflects albedo / pi of it back to the camera.
+        let (albedo, radiance, radius) = (0.8, 10.0, 0.25);
+        let mut list = HitableList::new();
+        let plane = Mesh::new(
+            vec![
+                Vector3::new(-5.0, -5.0, -3.0),
+                Vector3::new(5.0, -5.0, -3.0),
+                Vector3::new(5.0, 5.0, -3.0),
+                Vector3::new(-5.0, 5.0, -3.0),
+            ],
+            vec![[0, 1, 2], [0, 2, 3]],
+            Material::new(Vector3::repeat(albedo), LAMBERTIAN),
+        );
+        list.push_mesh(plane);
+        let light = Vector3::new(0.0, 1.0, -2.0);
+        list.push(Hitable::new(SPHERE, Sphere::new(light, radius), Material::emissive(Vector3::repeat(1.0), radiance)));
+        // A black map instead of the gradient sky
+        let environment = Environment::new(2, 1, vec![[0.0, 0.0, 0.0, 1.0]; 2]);
+        let scene = Scene::new(list)
+            .with_environment(environment)
+            .with_settings(settings(1, 1, 4096))
+            .with_camera(SceneCamera { vfov: 1.0, ..Default::default() });
+        let color = ReferenceRenderer::new(scene).trace_pixel(0, 0);
+
+        let to_light = light - Vector3::new(0.0, 0.0, -3.0);
+        let cos_theta = to_light.z / to_light.norm();
+        let expected = albedo * radiance * radius * radius / to_light.norm_squared() * cos_theta;
+        for c in color.iter() {
+            assert!((c - expected).abs() < 0.02 * expected, "radiance {} expected {}", c, expected);
+        }
+    }
+
+    #[test]
+    fn smooth_emitters_shine_from_their_geometric_front() {
+        // Vertex normals bent past the horizon don't change which side
+        // light sampling finds lit, so they can't change what the camera sees
+        let quad = |indices: Vec<[u32; 3]>, normal: Vector3<f32>| {
+            Mesh::new(
+                vec![
+                    Vector3::new(-1.0, -1.0, -2.0),
+                    Vector3::new(1.0, -1.0, -2.0),
+                    Vector3::new(1.0, 1.0, -2.0),
+                    Vector3::new(-1.0, 1.0, -2.0),
+                ],
+                indices,
+                Material::emissive(Vector3::repeat(1.0), 2.0),
+            )
+            .with_normals(vec![normal.normalize(); 4])
+        };
+        // Wound towards the camera with normals turned away, and the other way round
+        let front = quad(vec![[0, 1, 2], [0, 2, 3]], Vector3::new(0.0, 0.6, -0.8));
+        let back = quad(vec![[0, 2, 1], [0, 3, 2]], Vector3::new(0.0, 0.6, 0.8));
+        for (mesh, expected) in [(front, 2.0), (back, 0.0)] {
+            let mut list = HitableList::new();
+            list.push_mesh(mesh);
+            let scene = Scene::new(list)
+                .with_environment(Environment::new(2, 1, vec![[0.0, 0.0, 0.0, 1.0]; 2]))
+                .with_settings(settings(1, 1, 1))
+                .with_camera(SceneCamera { vfov: 1.0, ..Default::default() });
+            let color = ReferenceRenderer::new(scene).trace_pixel(0, 0);
+            assert_eq!(color, Vector3::repeat(expected));
+        }
+    }
+}
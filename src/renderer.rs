@@ -0,0 +1,1383 @@
+use std::borrow::Cow;
+use wgpu::util::DeviceExt;
+use bytemuck::Zeroable;
+use log::*;
+use crate::camera::Camera;
+use crate::hitable::*;
+use crate::scene::*;
+use crate::bvh::SceneBvh;
+use crate::light::LightList;
+use crate::environment::{Environment, EnvironmentSettings};
+use crate::sampler::{blue_noise, sobol_direction_numbers, BLUE_NOISE_SIZE, SOBOL_DIMENSIONS};
+
+// sRGB like the swapchain, so read back images match what a window shows
+const TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// Where `Renderer::step` draws to
+pub enum RenderTarget {
+    /// A window's swapchain, presented after every step
+    Surface {
+        surface: wgpu::Surface<'static>,
+        config: wgpu::SurfaceConfiguration,
+    },
+    /// An offscreen sRGB texture that `Renderer::read_image` copies back
+    Texture(wgpu::Texture),
+}
+
+impl RenderTarget {
+    pub fn size(&self) -> (u32, u32) {
+        match self {
+            RenderTarget::Surface { config, .. } => (config.width, config.height),
+            RenderTarget::Texture(texture) => (texture.width(), texture.height()),
+        }
+    }
+
+    pub fn format(&self) -> wgpu::TextureFormat {
+        match self {
+            RenderTarget::Surface { config, .. } => config.format,
+            RenderTarget::Texture(texture) => texture.format(),
+        }
+    }
+}
+
+/// Which GPU to render on when more than one is available
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum AdapterPreference {
+    #[default]
+    HighPerformance,
+    LowPower,
+    Fallback, // a software adapter such as lavapipe or llvmpipe
+}
+
+impl AdapterPreference {
+    /// Requests an adapter, which must be able to present to `compatible_surface` if given
+    pub async fn request_adapter(self, instance: &wgpu::Instance, compatible_surface: Option<&wgpu::Surface<'_>>) -> wgpu::Adapter {
+        self.try_request_adapter(instance, compatible_surface)
+            .await
+            .expect("Failed to find an appropriate adapter")
+    }
+
+    /// Like `request_adapter`, but None when the machine has no such adapter
+    pub async fn try_request_adapter(self, instance: &wgpu::Instance, compatible_surface: Option<&wgpu::Surface<'_>>) -> Option<wgpu::Adapter> {
+        instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: match self {
+                    AdapterPreference::LowPower => wgpu::PowerPreference::LowPower,
+                    _ => wgpu::PowerPreference::HighPerformance,
+                },
+                force_fallback_adapter: self == AdapterPreference::Fallback,
+                compatible_surface,
+            })
+            .await
+    }
+}
+
+impl std::str::FromStr for AdapterPreference {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "high-performance" => Ok(AdapterPreference::HighPerformance),
+            "low-power" => Ok(AdapterPreference::LowPower),
+            "fallback" => Ok(AdapterPreference::Fallback),
+            _ => Err(format!("unknown adapter {:?}, expected high-performance, low-power or fallback", name)),
+        }
+    }
+}
+
+/// How each iteration is traced, picked from what the device supports
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Integrator {
+    /// A compute pass reading the scene from storage buffers and writing the
+    /// average to a storage texture
+    Compute,
+    /// A fullscreen render pass reading the scene from data textures, for
+    /// WebGL2 and other adapters without compute shaders or storage buffers
+    Downlevel,
+}
+
+impl Integrator {
+    /// The compute integrator when the adapter can run it
+    pub fn supported(adapter: &wgpu::Adapter) -> Self {
+        let compute_shaders = adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS);
+        let storage_history = adapter
+            .get_texture_format_features(HISTORY_FORMAT)
+            .allowed_usages
+            .contains(wgpu::TextureUsages::STORAGE_BINDING);
+        if compute_shaders && storage_history {
+            Self::for_limits(&adapter.limits())
+        } else {
+            Integrator::Downlevel
+        }
+    }
+
+    /// The compute integrator when the limits allow its bindings and workgroups
+    pub fn for_limits(limits: &wgpu::Limits) -> Self {
+        if limits.max_storage_buffers_per_shader_stage >= STORAGE_BUFFERS
+            && limits.max_storage_textures_per_shader_stage >= 1
+            && limits.max_compute_workgroup_size_x >= WORKGROUP_SIZE
+            && limits.max_compute_workgroup_size_y >= WORKGROUP_SIZE
+            && limits.max_compute_invocations_per_workgroup >= WORKGROUP_SIZE * WORKGROUP_SIZE
+        {
+            Integrator::Compute
+        } else {
+            Integrator::Downlevel
+        }
+    }
+
+    /// The most precise format the integrator can write the average to
+    fn history_format(self, adapter: &wgpu::Adapter) -> wgpu::TextureFormat {
+        let renderable = adapter
+            .get_texture_format_features(HISTORY_FORMAT)
+            .allowed_usages
+            .contains(wgpu::TextureUsages::RENDER_ATTACHMENT);
+        match self {
+            Integrator::Compute => HISTORY_FORMAT,
+            Integrator::Downlevel if renderable => HISTORY_FORMAT,
+            Integrator::Downlevel => {
+                warn!("Rgba32Float isn't renderable on this adapter, averaging in half floats that stall after a few hundred iterations");
+                DOWNLEVEL_HISTORY_FORMAT
+            }
+        }
+    }
+
+    fn stages(self) -> wgpu::ShaderStages {
+        match self {
+            Integrator::Compute => wgpu::ShaderStages::COMPUTE,
+            Integrator::Downlevel => wgpu::ShaderStages::FRAGMENT,
+        }
+    }
+
+    /// How the hitables, meshes, lights and BVH are bound
+    fn scene_data_binding(self) -> wgpu::BindingType {
+        match self {
+            Integrator::Compute => wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            Integrator::Downlevel => wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Uint,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for Integrator {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "compute" => Ok(Integrator::Compute),
+            "downlevel" => Ok(Integrator::Downlevel),
+            _ => Err(format!("unknown integrator {:?}, expected compute or downlevel", name)),
+        }
+    }
+}
+
+// Hitables, vertices, indices, texels, lights, BVH nodes and instances
+const STORAGE_BUFFERS: u32 = 7;
+
+/// How far the running average in the history textures has got
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Accumulation {
+    /// The camera, scene, settings or size changed, so the next step clears
+    /// the history and starts over
+    Reset,
+    /// This many iterations are averaged so far, short of the target
+    Refining(u32),
+    /// The target was reached, steps do nothing until something changes
+    Converged(u32),
+}
+
+impl Accumulation {
+    /// Iterations averaged into the history so far
+    pub fn iterations(self) -> u32 {
+        match self {
+            Accumulation::Reset => 0,
+            Accumulation::Refining(iterations) | Accumulation::Converged(iterations) => iterations,
+        }
+    }
+
+    /// The state after tracing one more iteration towards `target`
+    fn advance(self, target: u32) -> Self {
+        Self::towards(self.iterations() + 1, target)
+    }
+
+    /// The state once the target changes, keeping what's been averaged
+    fn retarget(self, target: u32) -> Self {
+        match self {
+            Accumulation::Reset => Accumulation::Reset,
+            _ => Self::towards(self.iterations(), target),
+        }
+    }
+
+    fn towards(iterations: u32, target: u32) -> Self {
+        if iterations >= target.max(1) {
+            Accumulation::Converged(iterations)
+        } else {
+            Accumulation::Refining(iterations)
+        }
+    }
+}
+
+/// A device together with the integrator renderers created on it trace with
+pub struct RenderDevice {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    pub integrator: Integrator,
+    history_format: wgpu::TextureFormat,
+}
+
+impl RenderDevice {
+    /// Wraps a device the caller requested, which needs at least the limits
+    /// `Renderer::request_device` asks for. The integrator defaults to
+    /// `Integrator::supported` by the adapter the device came from.
+    pub fn new(adapter: &wgpu::Adapter, device: wgpu::Device, queue: wgpu::Queue, integrator: Option<Integrator>) -> Self {
+        let integrator = integrator.unwrap_or_else(|| Integrator::supported(adapter));
+        Self {
+            device,
+            queue,
+            integrator,
+            history_format: integrator.history_format(adapter),
+        }
+    }
+}
+
+/// Progressive path tracer for a `Scene`. Every `step` traces one more
+/// iteration of `samples_per_pixel` samples into the target and averages it
+/// with the ones before, until `RenderSettings::iterations` are averaged or
+/// the camera, scene, settings or size change and accumulation starts over.
+pub struct Renderer {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    target: RenderTarget,
+    camera: Camera,
+    initial_camera: SceneCamera,
+    settings: RenderSettings,
+    accumulation: Accumulation,
+    integrator: Integrator,
+    history_format: wgpu::TextureFormat,
+    trace_pipeline: TracePipeline,
+    blit_pipeline: wgpu::RenderPipeline,
+    camera_buffer: wgpu::Buffer,
+    camera_bind_group: wgpu::BindGroup,
+    history_layouts: HistoryLayouts,
+    history: History,
+    hitable_list_bind_group_layout: wgpu::BindGroupLayout,
+    hitable_list_bind_group: wgpu::BindGroup,
+    bvh_bind_group_layout: wgpu::BindGroupLayout,
+    bvh_bind_group: wgpu::BindGroup,
+}
+
+impl Renderer {
+    /// Requests a device with our limits, clamped to what the adapter supports
+    /// so software adapters like llvmpipe and lavapipe work too. The
+    /// integrator defaults to `Integrator::supported`, renderers created on
+    /// the device use the one it was requested for.
+    pub async fn request_device(adapter: &wgpu::Adapter, integrator: Option<Integrator>) -> RenderDevice {
+        let supported = adapter.limits();
+        let integrator = integrator.unwrap_or_else(|| Integrator::supported(adapter));
+        info!("Using the {:?} integrator", integrator);
+        let limits = match integrator {
+            Integrator::Compute => wgpu::Limits {
+                max_storage_buffer_binding_size: supported.max_storage_buffer_binding_size.min(512_u32 << 20),
+                ..Default::default()
+            },
+            Integrator::Downlevel => wgpu::Limits::downlevel_webgl2_defaults(),
+        };
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    required_features: wgpu::Features::empty(),
+                    // Make sure we use the texture resolution limits from the adapter, so we can support images the size of the swapchain.
+                    required_limits: limits.using_resolution(supported),
+                    memory_hints: wgpu::MemoryHints::MemoryUsage,
+                },
+                None,
+            )
+            .await
+            .expect("Failed to create device");
+        RenderDevice::new(adapter, device, queue, Some(integrator))
+    }
+
+    /// Renders into a window's surface with the given configuration, see
+    /// `wgpu::Surface::get_default_config`.
+    pub fn for_surface(
+        device: RenderDevice,
+        surface: wgpu::Surface<'static>,
+        config: wgpu::SurfaceConfiguration,
+        scene: Scene,
+    ) -> Self {
+        surface.configure(&device.device, &config);
+        Self::new(device, scene, RenderTarget::Surface { surface, config })
+    }
+
+    /// Renders offscreen, for reading the image back without a window
+    pub fn for_texture(device: RenderDevice, scene: Scene, width: u32, height: u32) -> Self {
+        let texture = create_target_texture(&device.device, width, height);
+        Self::new(device, scene, RenderTarget::Texture(texture))
+    }
+
+    fn new(device: RenderDevice, scene: Scene, target: RenderTarget) -> Self {
+        let (width, height) = target.size();
+
+        let RenderDevice { device, queue, integrator, history_format } = device;
+        let visibility = integrator.stages();
+        let settings = scene.settings;
+        let camera = Camera::new(width, height as f32, &scene.camera, &settings);
+        let camera_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Camera Buffer"),
+                contents: bytemuck::cast_slice(&[camera]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        // What the Sobol and blue noise samplers read, the same for every scene
+        let sobol_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Sobol Direction Buffer"),
+                contents: bytemuck::cast_slice(&sobol_direction_numbers(SOBOL_DIMENSIONS)),
+                usage: wgpu::BufferUsages::UNIFORM,
+            }
+        );
+        let blue_noise_texture = device.create_texture_with_data(
+            &queue,
+            &wgpu::TextureDescriptor {
+                label: Some("Blue Noise Texture"),
+                size: wgpu::Extent3d {
+                    width: BLUE_NOISE_SIZE as u32,
+                    height: BLUE_NOISE_SIZE as u32,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R32Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            bytemuck::cast_slice(blue_noise()),
+        );
+        let blue_noise_view = blue_noise_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let camera_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("camera_bind_group_layout"),
+        });
+
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &camera_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &camera_buffer,
+                        offset: 0,
+                        size: None,
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: sobol_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&blue_noise_view),
+                },
+            ],
+            label: Some("camera_bind_group"),
+        });
+
+        let hitable_list_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility,
+                    ty: integrator.scene_data_binding(),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility,
+                    ty: integrator.scene_data_binding(),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility,
+                    ty: integrator.scene_data_binding(),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility,
+                    ty: integrator.scene_data_binding(),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility,
+                    ty: integrator.scene_data_binding(),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("hitable_list_bind_group_layout"),
+        });
+
+        let bvh_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility,
+                    ty: integrator.scene_data_binding(),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility,
+                    ty: integrator.scene_data_binding(),
+                    count: None,
+                },
+            ],
+            label: Some("bvh_bind_group_layout"),
+        });
+
+        let (hitable_list_bind_group, bvh_bind_group) = create_scene_bind_groups(
+            &device,
+            &queue,
+            integrator,
+            &hitable_list_bind_group_layout,
+            &bvh_bind_group_layout,
+            scene.hitable_list,
+            scene.environment,
+        );
+
+        let history_layouts = create_history_layouts(&device, integrator);
+        let history = History::new(&device, &history_layouts, integrator, history_format, width, height);
+
+        // Load the shaders from disk, the integrator's bindings and entry
+        // point follow the shared path tracing code
+        let source = match integrator {
+            Integrator::Compute => concat!(include_str!("shader.wgsl"), include_str!("compute.wgsl")),
+            Integrator::Downlevel => concat!(include_str!("shader.wgsl"), include_str!("downlevel.wgsl")),
+        };
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(source)),
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[
+                &camera_bind_group_layout,
+                &hitable_list_bind_group_layout,
+                &history_layouts.trace,
+                &bvh_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let trace_pipeline = match integrator {
+            Integrator::Compute => TracePipeline::Compute(device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Trace Pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: "cs_main",
+                compilation_options: Default::default(),
+                cache: None,
+            })),
+            Integrator::Downlevel => TracePipeline::Downlevel(device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Trace Pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                    compilation_options: Default::default(),
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    compilation_options: Default::default(),
+                    targets: &[Some(history_format.into())],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+                cache: None,
+            })),
+        };
+
+        let blit_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("blit"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("blit.wgsl"))),
+        });
+
+        let blit_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&history_layouts.blit],
+            push_constant_ranges: &[],
+        });
+
+        let blit_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Blit Pipeline"),
+            layout: Some(&blit_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &blit_shader,
+                entry_point: "vs_main",
+                buffers: &[],
+                compilation_options: Default::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &blit_shader,
+                entry_point: "fs_main",
+                compilation_options: Default::default(),
+                targets: &[Some(target.format().into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        Self {
+            device,
+            queue,
+            target,
+            camera,
+            initial_camera: scene.camera,
+            settings,
+            accumulation: Accumulation::Reset,
+            integrator,
+            history_format,
+            trace_pipeline,
+            blit_pipeline,
+            camera_buffer,
+            camera_bind_group,
+            history_layouts,
+            history,
+            hitable_list_bind_group_layout,
+            hitable_list_bind_group,
+            bvh_bind_group_layout,
+            bvh_bind_group,
+        }
+    }
+
+    /// Replaces everything being rendered, including the camera
+    pub fn set_scene(&mut self, scene: Scene) {
+        let (hitable_list_bind_group, bvh_bind_group) = create_scene_bind_groups(
+            &self.device,
+            &self.queue,
+            self.integrator,
+            &self.hitable_list_bind_group_layout,
+            &self.bvh_bind_group_layout,
+            scene.hitable_list,
+            scene.environment,
+        );
+        self.hitable_list_bind_group = hitable_list_bind_group;
+        self.bvh_bind_group = bvh_bind_group;
+        self.initial_camera = scene.camera;
+        self.settings = scene.settings;
+        self.set_camera(scene.camera);
+    }
+
+    pub fn camera(&self) -> SceneCamera {
+        SceneCamera {
+            center: self.camera.center,
+            rotation: self.camera.rotation,
+            vfov: self.camera.vfov,
+        }
+    }
+
+    /// Moves the camera and starts accumulating again
+    pub fn set_camera(&mut self, camera: SceneCamera) {
+        let (width, height) = self.target.size();
+        self.camera = Camera::new(width, height as f32, &camera, &self.settings);
+        self.reset();
+    }
+
+    /// Returns to the scene's own camera
+    pub fn reset_camera(&mut self) {
+        self.set_camera(self.initial_camera);
+    }
+
+    /// Throws away the accumulated image, the next step starts over
+    pub fn reset(&mut self) {
+        self.accumulation = Accumulation::Reset;
+    }
+
+    pub fn settings(&self) -> RenderSettings {
+        self.settings
+    }
+
+    /// Changes how the scene is rendered. Changing only the iteration target
+    /// keeps the image, refining it further when the target went up.
+    pub fn set_settings(&mut self, settings: RenderSettings) {
+        let retargeted = RenderSettings { iterations: settings.iterations, ..self.settings } == settings;
+        self.settings = settings;
+        if retargeted {
+            self.accumulation = self.accumulation.retarget(settings.iterations);
+        } else {
+            self.set_camera(self.camera());
+        }
+    }
+
+    pub fn accumulation(&self) -> Accumulation {
+        self.accumulation
+    }
+
+    /// True once the iteration target is reached, `step` does nothing until
+    /// something changes
+    pub fn is_converged(&self) -> bool {
+        matches!(self.accumulation, Accumulation::Converged(_))
+    }
+
+    /// Iterations accumulated so far, each `samples_per_pixel` rays per pixel
+    pub fn iteration(&self) -> u32 {
+        self.accumulation.iterations()
+    }
+
+    /// Samples per pixel accumulated so far
+    pub fn sample_count(&self) -> u32 {
+        self.iteration() * self.camera.samples_per_pixel
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.target.size()
+    }
+
+    pub fn integrator(&self) -> Integrator {
+        self.integrator
+    }
+
+    pub fn target(&self) -> &RenderTarget {
+        &self.target
+    }
+
+    #[cfg(test)]
+    pub(crate) fn history_size(&self) -> (u32, u32) {
+        self.history.size
+    }
+
+    /// Resizes the target, the camera keeps looking at the same place
+    pub fn resize(&mut self, width: u32, height: u32) {
+        let (width, height) = (width.max(1), height.max(1));
+        match &mut self.target {
+            RenderTarget::Surface { surface, config } => {
+                config.width = width;
+                config.height = height;
+                surface.configure(&self.device, config);
+            }
+            RenderTarget::Texture(texture) => {
+                *texture = create_target_texture(&self.device, width, height);
+            }
+        }
+        self.history = History::new(&self.device, &self.history_layouts, self.integrator, self.history_format, width, height);
+        self.set_camera(self.camera());
+    }
+
+    /// Traces one more iteration into the target, presenting it when it's a
+    /// surface. Does nothing once converged.
+    pub fn step(&mut self) -> Result<(), wgpu::SurfaceError> {
+        if self.is_converged() {
+            return Ok(());
+        }
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
+        debug_assert_eq!(self.history.size, self.target.size(), "the history wasn't resized with the target");
+        // The shader weighs the history by iteration - 1, and ignores it on the first pass
+        self.camera.iteration = self.accumulation.iterations() + 1;
+        self.write_camera();
+        match &self.target {
+            RenderTarget::Surface { surface, .. } => {
+                let frame = surface.get_current_texture()?;
+                let view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
+                self.encode(&mut encoder, &view);
+                self.queue.submit(Some(encoder.finish()));
+                frame.present();
+            }
+            RenderTarget::Texture(texture) => {
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                self.encode(&mut encoder, &view);
+                self.queue.submit(Some(encoder.finish()));
+            }
+        }
+        self.history.swap();
+        self.accumulation = self.accumulation.advance(self.settings.iterations);
+        Ok(())
+    }
+
+    /// Copies the accumulated image back from a texture target. Surfaces
+    /// can't be read back, so they give None.
+    pub fn read_image(&self) -> Option<image::RgbaImage> {
+        match &self.target {
+            RenderTarget::Texture(texture) => Some(read_texture(&self.device, &self.queue, texture)),
+            RenderTarget::Surface { .. } => None,
+        }
+    }
+
+    /// Records a pass that traces one iteration into the history, and a
+    /// render pass that shows the new average on `view`
+    fn encode(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        let (width, height) = self.target.size();
+        match &self.trace_pipeline {
+            TracePipeline::Compute(pipeline) => {
+                let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("Trace Pass"),
+                    timestamp_writes: None,
+                });
+                cpass.set_pipeline(pipeline);
+                cpass.set_bind_group(0, &self.camera_bind_group, &[]);
+                cpass.set_bind_group(1, &self.hitable_list_bind_group, &[]);
+                cpass.set_bind_group(2, self.history.trace_bind_group(), &[]);
+                cpass.set_bind_group(3, &self.bvh_bind_group, &[]);
+                cpass.dispatch_workgroups(workgroup_count(width), workgroup_count(height), 1);
+            }
+            TracePipeline::Downlevel(pipeline) => {
+                // Every pixel is written, so there's nothing to load
+                let mut tpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Trace Pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: self.history.back_view(),
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                });
+                tpass.set_pipeline(pipeline);
+                tpass.set_bind_group(0, &self.camera_bind_group, &[]);
+                tpass.set_bind_group(1, &self.hitable_list_bind_group, &[]);
+                tpass.set_bind_group(2, self.history.trace_bind_group(), &[]);
+                tpass.set_bind_group(3, &self.bvh_bind_group, &[]);
+                tpass.draw(0..3, 0..1);
+            }
+        }
+        let mut rpass =
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Blit Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+        rpass.set_pipeline(&self.blit_pipeline);
+        rpass.set_bind_group(0, self.history.blit_bind_group(), &[]);
+        rpass.draw(0..3, 0..1);
+    }
+
+    fn write_camera(&self) {
+        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera]));
+    }
+}
+
+enum TracePipeline {
+    Compute(wgpu::ComputePipeline), // cs_main in compute.wgsl
+    Downlevel(wgpu::RenderPipeline), // vs_main and fs_main in downlevel.wgsl
+}
+
+// Matches @workgroup_size in cs_main
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Workgroups along one axis so every pixel gets an invocation, the
+/// shader skips the ones past the edge
+fn workgroup_count(pixels: u32) -> u32 {
+    pixels.div_ceil(WORKGROUP_SIZE)
+}
+
+// One rgba32float running average per pixel, stored to by the compute integrator
+// and rendered to by the downlevel one where the adapter allows it
+const HISTORY_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Float;
+// For GL drivers that can render half floats but not floats, such as WebGL2
+// with EXT_color_buffer_half_float only. Rounding every step to 11 bits makes
+// the average stall once new samples move it less than half an ulp.
+const DOWNLEVEL_HISTORY_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+struct HistoryLayouts {
+    trace: wgpu::BindGroupLayout, // group 2 of the trace pass, previous and, for compute, next average
+    blit: wgpu::BindGroupLayout,
+}
+
+fn create_history_layouts(device: &wgpu::Device, integrator: Integrator) -> HistoryLayouts {
+    let average = |binding, visibility| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            view_dimension: wgpu::TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    };
+    let next = wgpu::BindGroupLayoutEntry {
+        binding: 1,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::StorageTexture {
+            access: wgpu::StorageTextureAccess::WriteOnly,
+            format: HISTORY_FORMAT,
+            view_dimension: wgpu::TextureViewDimension::D2,
+        },
+        count: None,
+    };
+    let trace_entries = match integrator {
+        Integrator::Compute => vec![average(0, wgpu::ShaderStages::COMPUTE), next],
+        // The next average is the trace pass's render target
+        Integrator::Downlevel => vec![average(0, wgpu::ShaderStages::FRAGMENT)],
+    };
+    let trace = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &trace_entries,
+        label: Some("history_trace_bind_group_layout"),
+    });
+    let blit = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[average(0, wgpu::ShaderStages::FRAGMENT)],
+        label: Some("history_blit_bind_group_layout"),
+    });
+    HistoryLayouts { trace, blit }
+}
+
+/// The running average, ping-ponged between two textures since a pass
+/// can't read and write the same texture portably. Each step reads the
+/// front texture and writes the back one, then they swap.
+struct History {
+    views: [wgpu::TextureView; 2],
+    trace_bind_groups: [wgpu::BindGroup; 2], // [i] reads texture i, and for compute writes the other
+    blit_bind_groups: [wgpu::BindGroup; 2], // [i] reads texture i
+    front: usize,
+    size: (u32, u32), // always the target's, resizing builds a new history
+}
+
+impl History {
+    fn new(device: &wgpu::Device, layouts: &HistoryLayouts, integrator: Integrator, format: wgpu::TextureFormat, width: u32, height: u32) -> Self {
+        let written_by = match integrator {
+            Integrator::Compute => wgpu::TextureUsages::STORAGE_BINDING,
+            Integrator::Downlevel => wgpu::TextureUsages::RENDER_ATTACHMENT,
+        };
+        let views = [0, 1].map(|_| {
+            device
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: Some("History Texture"),
+                    size: wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING | written_by,
+                    view_formats: &[],
+                })
+                .create_view(&wgpu::TextureViewDescriptor::default())
+        });
+        let trace_bind_groups = [0, 1].map(|i| {
+            let previous = wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&views[i]),
+            };
+            let next = wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&views[1 - i]),
+            };
+            let entries = match integrator {
+                Integrator::Compute => vec![previous, next],
+                Integrator::Downlevel => vec![previous],
+            };
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &layouts.trace,
+                entries: &entries,
+                label: Some("history_trace_bind_group"),
+            })
+        });
+        let blit_bind_groups = [0, 1].map(|i| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &layouts.blit,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&views[i]),
+                }],
+                label: Some("history_blit_bind_group"),
+            })
+        });
+        Self {
+            views,
+            trace_bind_groups,
+            blit_bind_groups,
+            front: 0,
+            size: (width, height),
+        }
+    }
+
+    /// Where the downlevel trace pass renders the new average
+    fn back_view(&self) -> &wgpu::TextureView {
+        &self.views[1 - self.front]
+    }
+
+    fn trace_bind_group(&self) -> &wgpu::BindGroup {
+        &self.trace_bind_groups[self.front]
+    }
+
+    /// Shows what the trace pass just wrote
+    fn blit_bind_group(&self) -> &wgpu::BindGroup {
+        &self.blit_bind_groups[1 - self.front]
+    }
+
+    fn swap(&mut self) {
+        self.front = 1 - self.front;
+    }
+}
+
+/// Uploads everything the shader reads about the scene, bind groups 1 and 3
+fn create_scene_bind_groups(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    integrator: Integrator,
+    hitable_list_bind_group_layout: &wgpu::BindGroupLayout,
+    bvh_bind_group_layout: &wgpu::BindGroupLayout,
+    hitable_list: HitableList,
+    environment: Option<Environment>,
+) -> (wgpu::BindGroup, wgpu::BindGroup) {
+    let mut hitable_list = hitable_list;
+    if hitable_list.hitables.is_empty() {
+        // Keep the buffers bindable, a zeroed hitable is a sphere of radius 0
+        hitable_list.push(Hitable::zeroed());
+    }
+    let lights = LightList::build(&hitable_list);
+    info!("Building BVH over {} hitables", hitable_list.hitables.len());
+    let bvh = SceneBvh::build(&mut hitable_list);
+    let instances = if bvh.instances.is_empty() {
+        vec![Instance::zeroed()]
+    } else {
+        bvh.instances
+    };
+    let hitable_list_buffer = SceneData::new(device, queue, integrator, "Hitable List Buffer", bytemuck::cast_slice(hitable_list.hitables.as_slice()));
+
+    // Bindings can't be empty, so scenes without meshes upload a single unused entry
+    let vertices = if hitable_list.vertices.is_empty() {
+        vec![MeshVertex::zeroed()]
+    } else {
+        hitable_list.vertices
+    };
+    let indices = if hitable_list.indices.is_empty() {
+        vec![0]
+    } else {
+        hitable_list.indices
+    };
+    let texels = if hitable_list.texels.is_empty() {
+        vec![0]
+    } else {
+        hitable_list.texels
+    };
+    let vertex_storage_buffer = SceneData::new(device, queue, integrator, "Mesh Vertex Buffer", bytemuck::cast_slice(vertices.as_slice()));
+    let index_storage_buffer = SceneData::new(device, queue, integrator, "Mesh Index Buffer", bytemuck::cast_slice(indices.as_slice()));
+
+    let texel_storage_buffer = SceneData::new(device, queue, integrator, "Texel Buffer", bytemuck::cast_slice(texels.as_slice()));
+
+    let light_buffer = SceneData::new(device, queue, integrator, "Light Buffer", &lights.to_bytes());
+
+    // Without a map the shader uses the gradient sky and never reads the texture
+    let (environment, environment_settings) = match environment {
+        Some(environment) => {
+            let environment = environment.fit(device.limits().max_texture_dimension_2d);
+            let settings = environment.settings();
+            (environment, settings)
+        }
+        None => (Environment::new(1, 1, vec![[0.0; 4]]), EnvironmentSettings::default()),
+    };
+    let environment_buffer = device.create_buffer_init(
+        &wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Buffer"),
+            contents: bytemuck::cast_slice(&[environment_settings]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        }
+    );
+    let environment_texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Environment Texture"),
+            size: wgpu::Extent3d {
+                width: environment.width,
+                height: environment.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(environment.pixels.as_slice()),
+    );
+    let environment_view = environment_texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let distribution = environment.distribution();
+    let cdf_texture = |label, width, height, data: &[f32]| {
+        device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R32Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            bytemuck::cast_slice(data),
+        )
+    };
+    let marginal_texture = cdf_texture("Environment Marginal CDF", distribution.height, 1, &distribution.marginal);
+    let conditional_texture = cdf_texture("Environment Conditional CDF", distribution.width, distribution.height, &distribution.conditional);
+    let marginal_view = marginal_texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let conditional_view = conditional_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    let hitable_list_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: hitable_list_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: hitable_list_buffer.binding_resource(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: vertex_storage_buffer.binding_resource(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: index_storage_buffer.binding_resource(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: texel_storage_buffer.binding_resource(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: light_buffer.binding_resource(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &environment_buffer,
+                    offset: 0,
+                    size: None,
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: wgpu::BindingResource::TextureView(&environment_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 7,
+                resource: wgpu::BindingResource::TextureView(&marginal_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 8,
+                resource: wgpu::BindingResource::TextureView(&conditional_view),
+            },
+        ],
+        label: Some("hitable_list_bind_group"),
+    });
+
+    let bvh_buffer = SceneData::new(device, queue, integrator, "BVH Node Buffer", bytemuck::cast_slice(bvh.nodes.as_slice()));
+
+    let instance_buffer = SceneData::new(device, queue, integrator, "Instance Buffer", bytemuck::cast_slice(instances.as_slice()));
+
+    let bvh_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: bvh_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: bvh_buffer.binding_resource(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: instance_buffer.binding_resource(),
+            },
+        ],
+        label: Some("bvh_bind_group"),
+    });
+
+    (hitable_list_bind_group, bvh_bind_group)
+}
+
+/// Scene data as the integrator reads it, a storage buffer or a data texture
+enum SceneData {
+    Buffer(wgpu::Buffer),
+    Texture(wgpu::TextureView),
+}
+
+impl SceneData {
+    fn new(device: &wgpu::Device, queue: &wgpu::Queue, integrator: Integrator, label: &str, contents: &[u8]) -> Self {
+        match integrator {
+            Integrator::Compute => SceneData::Buffer(device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some(label),
+                    contents,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                }
+            )),
+            Integrator::Downlevel => {
+                // Same bytes as the buffer, in rows of rgba32uint texels
+                let texels = contents.len().div_ceil(DATA_TEXEL_SIZE) as u32;
+                let max_dimension = device.limits().max_texture_dimension_2d;
+                let (width, height) = data_texture_size(texels, max_dimension);
+                if height > max_dimension {
+                    panic!("{} needs {} texels, more than a {}x{} data texture holds", label, texels, max_dimension, max_dimension);
+                }
+                let mut data = contents.to_vec();
+                data.resize((width * height) as usize * DATA_TEXEL_SIZE, 0);
+                let texture = device.create_texture_with_data(
+                    queue,
+                    &wgpu::TextureDescriptor {
+                        label: Some(label),
+                        size: wgpu::Extent3d {
+                            width,
+                            height,
+                            depth_or_array_layers: 1,
+                        },
+                        mip_level_count: 1,
+                        sample_count: 1,
+                        dimension: wgpu::TextureDimension::D2,
+                        format: wgpu::TextureFormat::Rgba32Uint,
+                        usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                        view_formats: &[],
+                    },
+                    wgpu::util::TextureDataOrder::LayerMajor,
+                    &data,
+                );
+                SceneData::Texture(texture.create_view(&wgpu::TextureViewDescriptor::default()))
+            }
+        }
+    }
+
+    fn binding_resource(&self) -> wgpu::BindingResource<'_> {
+        match self {
+            SceneData::Buffer(buffer) => buffer.as_entire_binding(),
+            SceneData::Texture(view) => wgpu::BindingResource::TextureView(view),
+        }
+    }
+}
+
+// Bytes in one rgba32uint texel of a data texture
+const DATA_TEXEL_SIZE: usize = 16;
+
+/// Width and height of a data texture holding `texels`, filling rows as
+/// wide as allowed. The height may exceed `max_dimension` if they don't fit.
+fn data_texture_size(texels: u32, max_dimension: u32) -> (u32, u32) {
+    let width = texels.clamp(1, max_dimension);
+    (width, texels.max(1).div_ceil(width))
+}
+
+fn create_target_texture(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Render Target"),
+        size: wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: TEXTURE_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    })
+}
+
+fn read_texture(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture) -> image::RgbaImage {
+    let (width, height) = (texture.width(), texture.height());
+    // Copies need rows padded to COPY_BYTES_PER_ROW_ALIGNMENT
+    let row_bytes = width * 4;
+    let padded_row_bytes = row_bytes.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback Buffer"),
+        size: padded_row_bytes as u64 * height as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Readback Encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row_bytes),
+                rows_per_image: Some(height),
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+    queue.submit(Some(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let (sender, receiver) = flume::bounded(1);
+    slice.map_async(wgpu::MapMode::Read, move |result| sender.send(result).unwrap());
+    device.poll(wgpu::Maintain::wait()).panic_on_timeout();
+    receiver.recv().unwrap().expect("Failed to map the readback buffer");
+
+    let mut pixels = Vec::with_capacity((row_bytes * height) as usize);
+    for row in slice.get_mapped_range().chunks(padded_row_bytes as usize) {
+        pixels.extend_from_slice(&row[..row_bytes as usize]);
+    }
+    buffer.unmap();
+    image::RgbaImage::from_raw(width, height, pixels).expect("readback size mismatch")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulation_converges_at_the_target() {
+        let mut accumulation = Accumulation::Reset;
+        for expected in 1..4 {
+            accumulation = accumulation.advance(4);
+            assert_eq!(accumulation, Accumulation::Refining(expected));
+        }
+        accumulation = accumulation.advance(4);
+        assert_eq!(accumulation, Accumulation::Converged(4));
+        // A target of zero still traces one iteration
+        assert_eq!(Accumulation::Reset.advance(0), Accumulation::Converged(1));
+    }
+
+    #[test]
+    fn workgroups_cover_every_pixel() {
+        for pixels in [1, 7, 8, 9, 600, 601, 1080, 2160] {
+            let count = workgroup_count(pixels);
+            assert!(count * WORKGROUP_SIZE >= pixels);
+            assert!((count - 1) * WORKGROUP_SIZE < pixels, "{} has a workgroup to spare", pixels);
+        }
+    }
+
+    #[test]
+    fn webgl2_limits_need_the_downlevel_integrator() {
+        assert_eq!(Integrator::for_limits(&wgpu::Limits::default()), Integrator::Compute);
+        assert_eq!(Integrator::for_limits(&wgpu::Limits::downlevel_webgl2_defaults()), Integrator::Downlevel);
+    }
+
+    #[test]
+    fn data_textures_hold_every_texel() {
+        assert_eq!(data_texture_size(0, 2048), (1, 1));
+        assert_eq!(data_texture_size(5, 2048), (5, 1));
+        assert_eq!(data_texture_size(2048, 2048), (2048, 1));
+        assert_eq!(data_texture_size(2049, 2048), (2048, 2));
+        let (width, height) = data_texture_size(5000, 64);
+        assert_eq!((width, height), (64, 79));
+        assert!(width * height >= 5000 && width * (height - 1) < 5000);
+    }
+
+    #[test]
+    fn retargeting_keeps_the_history() {
+        let converged = Accumulation::Converged(4);
+        assert_eq!(converged.retarget(8), Accumulation::Refining(4));
+        assert_eq!(Accumulation::Refining(4).retarget(2), Accumulation::Converged(4));
+        assert_eq!(Accumulation::Reset.retarget(8), Accumulation::Reset);
+    }
+}
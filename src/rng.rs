@@ -0,0 +1,136 @@
+/// The shader's random number generator, run on the CPU so its statistics
+/// can be tested without a GPU. Mirrors `rng_seed`, `rng_next_u32` and
+/// `rng_next_f32` in shader.wgsl, keep the two in step.
+///
+/// PCG with 32 bits of state: an LCG step permuted by RXS M XS (O'Neill 2014).
+/// Every bounce of every sample of a pixel starts its own stream, seeded by
+/// hashing the three indices together.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Pcg32 {
+    state: u32,
+}
+
+const MULTIPLIER: u32 = 747796405;
+const INCREMENT: u32 = 2891336453;
+
+impl Pcg32 {
+    /// The stream for one bounce of one sample of a pixel. The pixel index is
+    /// `y * width + x`, the sample counts every sample of every iteration, and
+    /// bounce 0 jitters the camera ray while bounce n + 1 scatters hit n.
+    pub fn new(pixel: u32, sample: u32, bounce: u32) -> Self {
+        Self {
+            state: pcg_hash(pixel.wrapping_add(pcg_hash(sample.wrapping_add(pcg_hash(bounce))))),
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state = self.state.wrapping_mul(MULTIPLIER).wrapping_add(INCREMENT);
+        pcg_permute(self.state)
+    }
+
+    /// Uniform in [0, 1), every value exact
+    pub fn next_f32(&mut self) -> f32 {
+        unit_float(self.next_u32())
+    }
+}
+
+/// Uniform in [0, 1) from the top 24 bits of a 32 bit fraction, the shader's `unit_float`
+pub fn unit_float(x: u32) -> f32 {
+    (x >> 8) as f32 * (1.0 / 16_777_216.0)
+}
+
+/// One PCG step as a hash (Jarzynski and Olano 2020)
+pub fn pcg_hash(x: u32) -> u32 {
+    pcg_permute(x.wrapping_mul(MULTIPLIER).wrapping_add(INCREMENT))
+}
+
+fn pcg_permute(state: u32) -> u32 {
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pearson's chi-squared statistic of counts expected to be equal
+    fn chi_squared(counts: &[u32]) -> f64 {
+        let expected = counts.iter().sum::<u32>() as f64 / counts.len() as f64;
+        counts.iter().map(|&c| (c as f64 - expected).powi(2) / expected).sum()
+    }
+
+    fn correlation(pairs: &[(f32, f32)]) -> f64 {
+        let n = pairs.len() as f64;
+        let mean = |f: fn(&(f32, f32)) -> f32| pairs.iter().map(|p| f(p) as f64).sum::<f64>() / n;
+        let (mx, my) = (mean(|p| p.0), mean(|p| p.1));
+        let (mut sxy, mut sxx, mut syy) = (0.0, 0.0, 0.0);
+        for &(x, y) in pairs {
+            let (dx, dy) = (x as f64 - mx, y as f64 - my);
+            sxy += dx * dy;
+            sxx += dx * dx;
+            syy += dy * dy;
+        }
+        sxy / (sxx * syy).sqrt()
+    }
+
+    #[test]
+    fn draws_are_uniform() {
+        let mut rng = Pcg32::new(12345, 0, 0);
+        let mut bins = [0; 64];
+        let (mut sum, mut sum_squares) = (0.0, 0.0);
+        let draws = 1 << 16;
+        for _ in 0..draws {
+            let u = rng.next_f32();
+            assert!((0.0..1.0).contains(&u));
+            bins[(u * 64.0) as usize] += 1;
+            sum += u as f64;
+            sum_squares += (u as f64).powi(2);
+        }
+        // 63 degrees of freedom, exceeded with probability 0.001
+        assert!(chi_squared(&bins) < 103.4, "chi squared {}", chi_squared(&bins));
+        let mean = sum / draws as f64;
+        let variance = sum_squares / draws as f64 - mean * mean;
+        assert!((mean - 0.5).abs() < 0.005, "mean {}", mean);
+        assert!((variance - 1.0 / 12.0).abs() < 0.002, "variance {}", variance);
+    }
+
+    #[test]
+    fn neighbouring_streams_are_uncorrelated() {
+        // The first draws of adjacent pixels, consecutive samples and
+        // consecutive bounces, where a float hash of the coordinates repeated
+        let first = |pixel, sample, bounce| Pcg32::new(pixel, sample, bounce).next_f32();
+        let pixels: Vec<_> = (0..20_000).map(|i| (first(i, 0, 0), first(i + 1, 0, 0))).collect();
+        let samples: Vec<_> = (0..20_000).map(|i| (first(7, i, 1), first(7, i + 1, 1))).collect();
+        let bounces: Vec<_> = (0..20_000).map(|i| (first(i, 3, 1), first(i, 3, 2))).collect();
+        for (name, pairs) in [("pixels", pixels), ("samples", samples), ("bounces", bounces)] {
+            let r = correlation(&pairs);
+            assert!(r.abs() < 0.03, "{} correlate by {}", name, r);
+        }
+    }
+
+    #[test]
+    fn image_of_first_draws_has_no_pattern() {
+        // One draw per pixel of a frame: the share below a half should be
+        // even over 16x16 blocks of the image, and the first two draws
+        // uniform over a 16x16 grid on the unit square
+        let (width, height) = (800u32, 640u32);
+        let per_block = (width / 16 * height / 16) as f64;
+        let mut below_half = vec![0; 16 * 16];
+        let mut cells = vec![0; 16 * 16];
+        for y in 0..height {
+            for x in 0..width {
+                let mut rng = Pcg32::new(y * width + x, 4, 0);
+                let (u, v) = (rng.next_f32(), rng.next_f32());
+                if u < 0.5 {
+                    below_half[(y * 16 / height * 16 + x * 16 / width) as usize] += 1;
+                }
+                cells[(v * 16.0) as usize * 16 + (u * 16.0) as usize] += 1;
+            }
+        }
+        // Binomial counts, each block contributes one degree of freedom
+        let blocks: f64 = below_half.iter().map(|&c| (c as f64 - per_block / 2.0).powi(2) / (per_block / 4.0)).sum();
+        // 256 and 255 degrees of freedom, exceeded with probability 0.001
+        assert!(blocks < 331.8, "blocks chi squared {}", blocks);
+        assert!(chi_squared(&cells) < 330.5, "cells chi squared {}", chi_squared(&cells));
+    }
+}
@@ -0,0 +1,271 @@
+use std::sync::OnceLock;
+use serde::Deserialize;
+use crate::rng::{pcg_hash, Pcg32};
+
+/// Where the shader's pixel jitter, light samples and bounce directions come from
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Sampler {
+    /// Independent PCG draws, white noise
+    #[default]
+    Random,
+    /// Owen scrambled Sobol points, stratified over the samples of a pixel
+    Sobol,
+    /// The R2 sequence rotated per pixel by a blue noise tile, so what error
+    /// is left shows as high frequency noise
+    BlueNoise,
+}
+
+impl Sampler {
+    /// The shader's RANDOM_SAMPLER, SOBOL_SAMPLER or BLUE_NOISE_SAMPLER
+    pub fn id(self) -> u32 {
+        match self {
+            Sampler::Random => 0,
+            Sampler::Sobol => 1,
+            Sampler::BlueNoise => 2,
+        }
+    }
+}
+
+impl std::str::FromStr for Sampler {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "random" => Ok(Sampler::Random),
+            "sobol" => Ok(Sampler::Sobol),
+            "blue-noise" => Ok(Sampler::BlueNoise),
+            _ => Err(format!("unknown sampler {:?}, expected random, sobol or blue-noise", name)),
+        }
+    }
+}
+
+/// Sobol dimensions the shader reads, a 2D point per draw
+pub const SOBOL_DIMENSIONS: usize = 2;
+
+// Degree, polynomial coefficients and initial direction numbers of the
+// dimensions after the first (Joe and Kuo 2008, new-joe-kuo-6.21201)
+const SOBOL_POLYNOMIALS: [(usize, u32, [u32; 3]); 3] = [
+    (1, 0, [1, 0, 0]),
+    (2, 1, [1, 3, 0]),
+    (3, 1, [1, 3, 1]),
+];
+
+/// Direction numbers of the first `dimensions` Sobol dimensions, 32 per
+/// dimension with the most significant bit first, as the shader's
+/// `sobol_directions` holds them
+pub fn sobol_direction_numbers(dimensions: usize) -> Vec<u32> {
+    assert!(dimensions <= SOBOL_POLYNOMIALS.len() + 1, "no polynomial for Sobol dimension {}", dimensions - 1);
+    let mut numbers = Vec::with_capacity(dimensions * 32);
+    // The first dimension is the van der Corput sequence
+    numbers.extend((0..32).map(|bit| 1u32 << (31 - bit)));
+    for &(degree, coefficients, initial) in &SOBOL_POLYNOMIALS[..dimensions.saturating_sub(1)] {
+        let mut v = [0u32; 32];
+        for bit in 0..32 {
+            v[bit] = if bit < degree {
+                initial[bit] << (31 - bit)
+            } else {
+                let mut number = v[bit - degree] ^ (v[bit - degree] >> degree);
+                for k in 1..degree {
+                    if (coefficients >> (degree - 1 - k)) & 1 == 1 {
+                        number ^= v[bit - k];
+                    }
+                }
+                number
+            };
+        }
+        numbers.extend_from_slice(&v);
+    }
+    numbers
+}
+
+/// The `index`th Sobol point in one dimension, as a 32 bit fraction
+pub fn sobol(directions: &[u32], index: u32, dimension: usize) -> u32 {
+    let directions = &directions[dimension * 32..][..32];
+    (0..32)
+        .filter(|bit| (index >> bit) & 1 == 1)
+        .fold(0, |x, bit| x ^ directions[bit])
+}
+
+/// Owen scrambling of the bits of a 32 bit fraction by hashing, each bit
+/// flipped depending on the ones above it (Burley 2020)
+pub fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits().wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+
+/// The shader's `sobol_2d`: a 2D Owen scrambled Sobol point for one
+/// dimension of one sample of a pixel, its samples shuffled per pixel
+pub fn scrambled_sobol_2d(directions: &[u32], pixel: u32, sample: u32, dimension: u32) -> [u32; 2] {
+    let seed = pcg_hash(pixel.wrapping_add(pcg_hash(dimension)));
+    let index = nested_uniform_scramble(sample, seed);
+    [
+        nested_uniform_scramble(sobol(directions, index, 0), pcg_hash(seed)),
+        nested_uniform_scramble(sobol(directions, index, 1), pcg_hash(seed.wrapping_add(1))),
+    ]
+}
+
+/// The R2 sequence as 32 bit fractions, the shader's `r2`
+pub fn r2(index: u32) -> [u32; 2] {
+    [index.wrapping_mul(3242174889), index.wrapping_mul(2447445414)]
+}
+
+/// The shader's `blue_noise_2d`: the pixel's R2 point for a sample, rotated
+/// by the blue noise tile read at the dimension's own offset
+pub fn blue_noise_2d(pixel: [u32; 2], sample: u32, dimension: u32) -> [u32; 2] {
+    let size = BLUE_NOISE_SIZE as u32;
+    let tile = blue_noise();
+    let offset = r2(dimension + 1).map(|x| x >> 24);
+    let [x, y] = [0, 1].map(|i| (pixel[i] + offset[i]) % size);
+    let noise = [
+        tile[(y * size + x) as usize],
+        tile[((y + size / 2) % size * size + (x + size / 2) % size) as usize],
+    ];
+    let point = r2(sample);
+    [0, 1].map(|i| point[i].wrapping_add(((noise[i] * 16_777_216.0) as u32) << 8))
+}
+
+/// Width and height of the blue noise tile
+pub const BLUE_NOISE_SIZE: usize = 64;
+
+/// A tile of blue noise: every value in (0, 1) taken once, spread so that
+/// neighbours differ as much as possible. Made once by void and cluster
+/// (Ulichney 1993), and repeated over the image by the shader.
+pub fn blue_noise() -> &'static [f32] {
+    static TILE: OnceLock<Vec<f32>> = OnceLock::new();
+    TILE.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE))
+}
+
+fn void_and_cluster(size: usize) -> Vec<f32> {
+    let count = size * size;
+    // Gaussian energy each set pixel spreads to the others, wrapping around
+    let sigma = 1.5f32;
+    let kernel: Vec<f32> = (0..count)
+        .map(|i| {
+            let wrap = |d: usize| d.min(size - d) as f32;
+            let (dx, dy) = (wrap(i % size), wrap(i / size));
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let toggle = |pattern: &mut [bool], energy: &mut [f32], pixel: usize| {
+        pattern[pixel] = !pattern[pixel];
+        let sign = if pattern[pixel] { 1.0 } else { -1.0 };
+        let (px, py) = (pixel % size, pixel / size);
+        for (i, e) in energy.iter_mut().enumerate() {
+            let (dx, dy) = ((i % size + size - px) % size, (i / size + size - py) % size);
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+    // The set pixel with the most energy around it, or the unset one with the least
+    let tightest_cluster = |pattern: &[bool], energy: &[f32]| {
+        (0..count).filter(|&i| pattern[i]).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f32]| {
+        (0..count).filter(|&i| !pattern[i]).min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+
+    // A random tenth of the pixels, moved from clusters into voids until even
+    let mut pattern = vec![false; count];
+    let mut energy = vec![0.0; count];
+    let mut rng = Pcg32::new(0, 0, 0);
+    let mut ones = 0;
+    while ones < count / 10 {
+        let pixel = rng.next_u32() as usize % count;
+        if !pattern[pixel] {
+            toggle(&mut pattern, &mut energy, pixel);
+            ones += 1;
+        }
+    }
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, cluster);
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0; count];
+    // The initial pixels rank below it in the order clusters are removed
+    let (mut removing, mut removing_energy) = (pattern.clone(), energy.clone());
+    for r in (0..ones).rev() {
+        let cluster = tightest_cluster(&removing, &removing_energy);
+        toggle(&mut removing, &mut removing_energy, cluster);
+        rank[cluster] = r;
+    }
+    // and the rest above it, in the order voids are filled
+    for r in ones..count {
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        rank[void] = r;
+    }
+    rank.iter().map(|&r| (r as f32 + 0.5) / count as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(x: u32) -> f64 {
+        x as f64 / 4294967296.0
+    }
+
+    #[test]
+    fn sobol_starts_with_the_known_points() {
+        let directions = sobol_direction_numbers(SOBOL_DIMENSIONS);
+        let first: Vec<_> = (0..8).map(|i| [unit(sobol(&directions, i, 0)), unit(sobol(&directions, i, 1))]).collect();
+        assert_eq!(first, [
+            [0.0, 0.0], [0.5, 0.5], [0.25, 0.75], [0.75, 0.25],
+            [0.125, 0.625], [0.625, 0.125], [0.375, 0.375], [0.875, 0.875],
+        ]);
+        // Higher dimensions follow from their polynomials, matching Joe and
+        // Kuo's points once their Gray code order is undone
+        let directions = sobol_direction_numbers(4);
+        let point = |i| [0, 1, 2, 3].map(|d| unit(sobol(&directions, i, d)));
+        assert_eq!(point(3), [0.75, 0.25, 0.25, 0.25]);
+        assert_eq!(point(6), [0.375, 0.375, 0.625, 0.875]);
+    }
+
+    #[test]
+    fn scrambled_sobol_samples_stay_stratified() {
+        // The first 2^m samples of any pixel and dimension put one point in
+        // every cell of each 2^k by 2^(m-k) grid
+        let directions = sobol_direction_numbers(SOBOL_DIMENSIONS);
+        let m = 6;
+        for (pixel, dimension) in [(0, 0), (1, 0), (12345, 3), (799, 17)] {
+            let points: Vec<_> = (0..1 << m).map(|s| scrambled_sobol_2d(&directions, pixel, s, dimension)).collect();
+            for k in 0..=m {
+                let mut cells: Vec<_> = points.iter().map(|p| (p[0] as u64 >> (32 - k), p[1] as u64 >> (32 - (m - k)))).collect();
+                cells.sort();
+                cells.dedup();
+                assert_eq!(cells.len(), 1 << m, "pixel {} dimension {} with {} x bits", pixel, dimension, k);
+            }
+        }
+        // while pixels don't share points
+        let a = scrambled_sobol_2d(&directions, 0, 0, 0);
+        let b = scrambled_sobol_2d(&directions, 1, 0, 0);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn blue_noise_has_every_value_and_little_low_frequency() {
+        let tile = blue_noise();
+        let mut ranks: Vec<_> = tile.iter().map(|v| (v * tile.len() as f32) as usize).collect();
+        ranks.sort();
+        assert!(ranks.iter().enumerate().all(|(i, &r)| i == r));
+
+        // Averages over 4x4 blocks vary far less than the 1/192 of white noise
+        let size = BLUE_NOISE_SIZE;
+        let blocks: Vec<f64> = (0..size / 4)
+            .flat_map(|by| (0..size / 4).map(move |bx| (bx, by)))
+            .map(|(bx, by)| (0..16).map(|i| tile[(by * 4 + i / 4) * size + bx * 4 + i % 4] as f64).sum::<f64>() / 16.0)
+            .collect();
+        let variance = blocks.iter().map(|b| (b - 0.5).powi(2)).sum::<f64>() / blocks.len() as f64;
+        assert!(variance < 1.0 / 192.0 / 4.0, "block variance {}", variance);
+    }
+}
@@ -0,0 +1,150 @@
+use std::f32::consts::PI;
+use nalgebra::{Matrix3, Vector3};
+
+// Warps from the unit square to directions, as shader.wgsl does them. Keep
+// the two in step, the tests here check the shader's sampling through them.
+
+/// Uniformly distributed direction on the unit sphere, the shader's `random_unit_vector`
+pub fn unit_vector(u: [f32; 2]) -> Vector3<f32> {
+    let z = 1.0 - 2.0 * u[0];
+    let phi = 2.0 * PI * u[1];
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Direction about the normal with pdf cos(theta) / pi, the shader's
+/// `random_cosine_direction`
+pub fn cosine_direction(normal: &Vector3<f32>, u: [f32; 2]) -> Vector3<f32> {
+    let phi = 2.0 * PI * u[0];
+    let r = u[1].sqrt();
+    let local = Vector3::new(phi.cos() * r, phi.sin() * r, (1.0 - u[1]).sqrt());
+    onb(normal) * local
+}
+
+/// Solid angle density of `cosine_direction`, the pdf `scatter_lambertian` reports
+pub fn cosine_pdf(normal: &Vector3<f32>, direction: &Vector3<f32>) -> f32 {
+    normal.dot(direction).max(0.0) / PI
+}
+
+/// Orthonormal basis with n as the z axis (Duff et al. 2017)
+pub fn onb(n: &Vector3<f32>) -> Matrix3<f32> {
+    let s = if n.z >= 0.0 { 1.0 } else { -1.0 };
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    Matrix3::from_columns(&[
+        Vector3::new(1.0 + s * n.x * n.x * a, s * b, -s * n.x),
+        Vector3::new(b, s + n.y * n.y * a, -n.y),
+        *n,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Pcg32;
+
+    fn normals() -> Vec<Vector3<f32>> {
+        vec![
+            Vector3::z(),
+            -Vector3::z(),
+            Vector3::x(),
+            Vector3::new(0.3, -0.8, 0.52).normalize(),
+            Vector3::new(-0.01, 0.02, -1.0).normalize(),
+        ]
+    }
+
+    #[test]
+    fn cosine_directions_follow_their_pdf() {
+        let mut rng = Pcg32::new(0, 0, 0);
+        for normal in normals() {
+            let basis = onb(&normal);
+            assert!((basis.transpose() * basis - Matrix3::identity()).norm() < 1e-5);
+
+            // cos^2(theta) is uniform when directions are cosine weighted
+            let mut bins = [0u32; 16];
+            let mut cosine_sum = 0.0;
+            let draws = 40_000;
+            for _ in 0..draws {
+                let direction = cosine_direction(&normal, [rng.next_f32(), rng.next_f32()]);
+                assert!((direction.norm() - 1.0).abs() < 1e-4);
+                let cosine = normal.dot(&direction);
+                assert!(cosine >= -1e-6, "{:?} leaves the hemisphere of {:?}", direction, normal);
+                bins[((cosine * cosine * 16.0) as usize).min(15)] += 1;
+                cosine_sum += cosine as f64;
+            }
+            let expected = draws as f64 / 16.0;
+            let chi_squared: f64 = bins.iter().map(|&c| (c as f64 - expected).powi(2) / expected).sum();
+            // 15 degrees of freedom, exceeded with probability 0.001
+            assert!(chi_squared < 37.7, "chi squared {} about {:?}", chi_squared, normal);
+            // and the mean cosine is the integral of cos^2 / pi, 2/3
+            assert!((cosine_sum / draws as f64 - 2.0 / 3.0).abs() < 0.005);
+        }
+    }
+
+    #[test]
+    fn unit_vectors_cover_the_sphere_evenly() {
+        let mut rng = Pcg32::new(1, 0, 0);
+        let draws = 40_000;
+        let mut sum = Vector3::zeros();
+        let mut upper = 0;
+        for _ in 0..draws {
+            let v = unit_vector([rng.next_f32(), rng.next_f32()]);
+            assert!((v.norm() - 1.0).abs() < 1e-4);
+            sum += v;
+            upper += (v.dot(&Vector3::new(0.6, 0.0, 0.8)) > 0.0) as u32;
+        }
+        assert!((sum / draws as f32).norm() < 0.02);
+        assert!((upper as f32 / draws as f32 - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn white_furnace_converges() {
+        // A closed unit sphere of diffuse walls with albedo 0.75 emitting
+        // 1 + z. From any point on the wall, cosine weighted directions land
+        // uniformly over the sphere's area, so every wall point reflects
+        // 0.75 of the mean radiance 1 / (1 - 0.75) on top of what it emits,
+        // and looking from the center along d sees 4 + d.z. Any other
+        // distribution lands paths elsewhere and misses that.
+        let albedo = 0.75;
+        let emission = |p: &Vector3<f32>| 1.0 + p.z;
+        let mut rng = Pcg32::new(2, 0, 0);
+        for view in [Vector3::z(), -Vector3::z(), Vector3::x()] {
+            let paths = 40_000;
+            let mut total = 0.0;
+            for _ in 0..paths {
+                let mut origin: Vector3<f32> = Vector3::zeros();
+                let mut direction = view;
+                let mut throughput = 1.0;
+                let mut radiance = 0.0;
+                loop {
+                    // Leaving the unit sphere from inside: |origin + t direction| = 1
+                    let b = origin.dot(&direction);
+                    let c = origin.norm_squared() - 1.0;
+                    let t = -b + (b * b - c).max(0.0).sqrt();
+                    origin += t * direction;
+                    let normal = -origin.normalize();
+                    radiance += throughput * emission(&origin);
+
+                    // Russian roulette with the albedo as survival probability
+                    if rng.next_f32() >= albedo {
+                        break;
+                    }
+                    direction = cosine_direction(&normal, [rng.next_f32(), rng.next_f32()]);
+                    let cosine = normal.dot(&direction);
+                    if cosine <= 0.0 {
+                        break;
+                    }
+                    // The density the warp is meant to have, not cosine_pdf
+                    let pdf = cosine / PI;
+                    throughput *= albedo / PI * cosine / pdf / albedo;
+                    origin += normal * 1e-4;
+                }
+                total += radiance as f64;
+            }
+            let mean = total / paths as f64;
+            let expected = 4.0 + view.z as f64;
+            // The standard error is about 0.02
+            assert!((mean - expected).abs() < 0.08, "furnace radiance {} looking along {:?}, expected {}", mean, view, expected);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+use std::path::Path;
+use nalgebra::{Matrix4, Vector3};
+use serde::Deserialize;
+use crate::environment::Environment;
+use crate::hitable::HitableList;
+use crate::sampler::Sampler;
+
+/// Where the camera starts, and where the reset key returns it to.
+#[derive(Copy, Clone, Debug)]
+pub struct SceneCamera {
+    pub center: Vector3<f32>,
+    pub rotation: Matrix4<f32>,
+    pub vfov: f32, // vertical field of view in degrees
+}
+
+impl Default for SceneCamera {
+    fn default() -> Self {
+        Self {
+            center: Vector3::zeros(),
+            rotation: Matrix4::identity(),
+            vfov: 90.0,
+        }
+    }
+}
+
+/// How a scene is rendered. Scene files fill these in, anything missing
+/// keeps its default.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderSettings {
+    pub width: u32, // headless output size and the window's initial size
+    pub height: u32,
+    pub samples_per_pixel: u32, // rays per pixel in each iteration
+    pub max_depth: u32, // bounces before a path is cut off
+    pub iterations: u32, // accumulation stops after this many
+    pub sampler: Sampler,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: 800,
+            height: 600,
+            samples_per_pixel: 3,
+            max_depth: 10,
+            iterations: 50,
+            sampler: Sampler::default(),
+        }
+    }
+}
+
+/// Everything a `Renderer` draws: the objects with their materials, where
+/// the camera starts and what lights the scene from far away.
+#[derive(Clone, Debug, Default)]
+pub struct Scene {
+    pub hitable_list: HitableList,
+    pub camera: SceneCamera,
+    pub environment: Option<Environment>, // None keeps the gradient sky
+    pub settings: RenderSettings,
+}
+
+impl Scene {
+    pub fn new(hitable_list: HitableList) -> Self {
+        Self {
+            hitable_list,
+            camera: SceneCamera::default(),
+            environment: None,
+            settings: RenderSettings::default(),
+        }
+    }
+
+    pub fn with_camera(mut self, camera: SceneCamera) -> Self {
+        self.camera = camera;
+        self
+    }
+
+    pub fn with_environment(mut self, environment: Environment) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    pub fn with_settings(mut self, settings: RenderSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Loads a scene description (`.toml`), glTF (`.gltf`, `.glb`) or
+    /// Wavefront OBJ file, picked by extension
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SceneError> {
+        let path = path.as_ref();
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => crate::scene_file::load_scene_file(path),
+            Some("gltf") | Some("glb") => crate::gltf::load_gltf(path).map_err(SceneError::Gltf),
+            _ => crate::obj::load_obj(path).map(Scene::new).map_err(SceneError::Obj),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Obj(tobj::LoadError),
+    Gltf(::gltf::Error),
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Image(image::ImageError),
+    Invalid(String), // parsed, but refers to something that doesn't exist
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Obj(err) => write!(f, "{}", err),
+            SceneError::Gltf(err) => write!(f, "{}", err),
+            SceneError::Io(err) => write!(f, "{}", err),
+            SceneError::Toml(err) => write!(f, "{}", err),
+            SceneError::Image(err) => write!(f, "{}", err),
+            SceneError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
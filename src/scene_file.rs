@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use log::*;
+use nalgebra::{Matrix4, Rotation3, Vector3};
+use serde::Deserialize;
+use crate::environment::Environment;
+use crate::hitable::*;
+use crate::scene::*;
+
+/// Loads a TOML scene description. Paths inside it are relative to the file.
+///
+/// ```toml
+/// [render]
+/// width = 800
+/// height = 600
+/// samples_per_pixel = 3
+/// max_depth = 10
+/// iterations = 50
+/// sampler = "sobol" # random, sobol or blue-noise
+///
+/// [camera]
+/// center = [0.0, 1.0, 3.0]
+/// look_at = [0.0, 0.0, -1.0]
+/// vfov = 40.0
+///
+/// [environment]
+/// path = "sky.hdr"
+/// rotation = 90.0
+/// intensity = 1.5
+///
+/// [materials.ground]
+/// albedo = [0.8, 0.8, 0.0]
+///
+/// [materials.lamp]
+/// kind = "emissive"
+/// emission = [1.0, 0.9, 0.8]
+/// strength = 10.0
+///
+/// [[objects]]
+/// type = "sphere"
+/// center = [0.0, -100.5, -1.0]
+/// radius = 100.0
+/// material = "ground"
+///
+/// [[objects]]
+/// type = "mesh"
+/// path = "bunny.obj" # .obj, .gltf or .glb
+/// translate = [0.0, 0.0, -1.0]
+/// rotate = [0.0, 45.0, 0.0]
+/// scale = 0.5
+/// material = "lamp" # optional, replaces the file's own materials
+/// ```
+pub fn load_scene_file<P: AsRef<Path>>(path: P) -> Result<Scene, SceneError> {
+    let path = path.as_ref();
+    info!("Loading {:?}", path);
+    let text = std::fs::read_to_string(path).map_err(SceneError::Io)?;
+    let file: SceneFile = toml::from_str(&text).map_err(SceneError::Toml)?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    file.into_scene(base)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    #[serde(default)]
+    render: RenderSettings,
+    camera: Option<CameraDesc>,
+    environment: Option<EnvironmentDesc>,
+    #[serde(default)]
+    materials: HashMap<String, MaterialDesc>,
+    #[serde(default)]
+    objects: Vec<ObjectDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    #[serde(default)]
+    center: [f32; 3],
+    look_at: Option<[f32; 3]>, // looks down -z when missing
+    #[serde(default = "up")]
+    up: [f32; 3],
+    #[serde(default = "vfov")]
+    vfov: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDesc {
+    path: PathBuf,
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default = "one")]
+    intensity: f32,
+}
+
+#[derive(Deserialize, Default, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum MaterialKind {
+    #[default]
+    Lambertian,
+    Metal,
+    Dielectric,
+    Emissive,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDesc {
+    #[serde(default)]
+    kind: MaterialKind,
+    #[serde(default = "albedo")]
+    albedo: [f32; 3],
+    #[serde(default)]
+    roughness: f32,
+    #[serde(default = "ior")]
+    ior: f32,
+    #[serde(default)]
+    emission: [f32; 3],
+    #[serde(default = "one")]
+    strength: f32,
+    texture: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum ObjectDesc {
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+        material: String,
+    },
+    Mesh {
+        path: PathBuf,
+        material: Option<String>,
+        #[serde(default)]
+        translate: [f32; 3],
+        #[serde(default)]
+        rotate: [f32; 3], // degrees about x, y then z
+        #[serde(default)]
+        scale: Scale,
+    },
+}
+
+#[derive(Deserialize, Copy, Clone)]
+#[serde(untagged)]
+enum Scale {
+    Uniform(f32),
+    Axes([f32; 3]),
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Scale::Uniform(1.0)
+    }
+}
+
+fn up() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn vfov() -> f32 {
+    90.0
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn albedo() -> [f32; 3] {
+    [0.8, 0.8, 0.8]
+}
+
+fn ior() -> f32 {
+    1.5
+}
+
+impl SceneFile {
+    fn into_scene(self, base: &Path) -> Result<Scene, SceneError> {
+        let mut hitable_list = HitableList::new();
+        let mut materials = HashMap::new();
+        for (name, desc) in &self.materials {
+            materials.insert(name.as_str(), desc.to_material(base, &mut hitable_list)?);
+        }
+        let material = |name: &str| {
+            materials
+                .get(name)
+                .copied()
+                .ok_or_else(|| SceneError::Invalid(format!("unknown material {:?}", name)))
+        };
+
+        for object in &self.objects {
+            match object {
+                ObjectDesc::Sphere { center, radius, material: name } => {
+                    let sphere = Sphere::new(Vector3::from(*center), *radius);
+                    hitable_list.push(Hitable::new(SPHERE, sphere, material(name)?));
+                }
+                ObjectDesc::Mesh { path, material: name, translate, rotate, scale } => {
+                    let override_material = name.as_deref().map(material).transpose()?;
+                    // Only the hitables are kept, and a scene file could name itself
+                    let extension = path.extension().and_then(|extension| extension.to_str());
+                    if !matches!(extension.map(str::to_ascii_lowercase).as_deref(), Some("obj" | "gltf" | "glb")) {
+                        return Err(SceneError::Invalid(format!("mesh {:?} isn't an .obj, .gltf or .glb file", path)));
+                    }
+                    let loaded = Scene::load(base.join(path))?;
+                    let [rx, ry, rz] = rotate.map(f32::to_radians);
+                    let scale = match *scale {
+                        Scale::Uniform(s) => Vector3::repeat(s),
+                        Scale::Axes(axes) => Vector3::from(axes),
+                    };
+                    let transform = Matrix4::new_translation(&Vector3::from(*translate))
+                        * Rotation3::from_euler_angles(rx, ry, rz).to_homogeneous()
+                        * Matrix4::new_nonuniform_scaling(&scale);
+                    hitable_list.append(loaded.hitable_list, transform, override_material);
+                }
+            }
+        }
+
+        let mut scene = Scene::new(hitable_list).with_settings(self.render);
+        if let Some(desc) = self.camera {
+            scene = scene.with_camera(desc.to_camera());
+        }
+        if let Some(desc) = self.environment {
+            let path = base.join(&desc.path);
+            let environment = Environment::load(&path)
+                .map_err(SceneError::Image)?
+                .with_rotation(desc.rotation)
+                .with_intensity(desc.intensity);
+            scene = scene.with_environment(environment);
+        }
+        Ok(scene)
+    }
+}
+
+impl CameraDesc {
+    fn to_camera(&self) -> SceneCamera {
+        let center = Vector3::from(self.center);
+        let rotation = match self.look_at {
+            // face_towards points +z at the target, the camera looks down -z
+            Some(target) => Rotation3::face_towards(&(center - Vector3::from(target)), &Vector3::from(self.up))
+                .to_homogeneous(),
+            None => Matrix4::identity(),
+        };
+        SceneCamera {
+            center,
+            rotation,
+            vfov: self.vfov,
+        }
+    }
+}
+
+impl MaterialDesc {
+    fn to_material(&self, base: &Path, hitable_list: &mut HitableList) -> Result<Material, SceneError> {
+        let albedo = Vector3::from(self.albedo);
+        let mut material = match self.kind {
+            MaterialKind::Lambertian => Material::new(albedo, LAMBERTIAN),
+            MaterialKind::Metal => Material::new(albedo, METAL).with_roughness(self.roughness),
+            MaterialKind::Dielectric => Material::dielectric(self.ior),
+            MaterialKind::Emissive => Material::emissive(Vector3::from(self.emission), self.strength),
+        };
+        if !matches!(self.kind, MaterialKind::Emissive) && self.emission != [0.0; 3] {
+            material = material.with_emission(Vector3::from(self.emission), self.strength);
+        }
+        if let Some(path) = &self.texture {
+            let image = image::open(base.join(path)).map_err(SceneError::Image)?.into_rgba8();
+            let texels: Vec<[u8; 4]> = image.pixels().map(|pixel| pixel.0).collect();
+            let texture = hitable_list.add_texture(image.width(), image.height(), &texels);
+            material = material.with_texture(texture);
+        }
+        Ok(material)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Scene, SceneError> {
+        let file: SceneFile = toml::from_str(text).map_err(SceneError::Toml)?;
+        file.into_scene(Path::new(""))
+    }
+
+    #[test]
+    fn spheres_materials_and_settings() {
+        let scene = parse(r#"
+            [render]
+            width = 320
+            samples_per_pixel = 8
+            sampler = "blue-noise"
+
+            [camera]
+            center = [0.0, 0.0, 2.0]
+            look_at = [0.0, 0.0, 0.0]
+            vfov = 45.0
+
+            [materials.glass]
+            kind = "dielectric"
+            ior = 1.33
+
+            [materials.lamp]
+            kind = "emissive"
+            emission = [1.0, 0.5, 0.25]
+            strength = 4.0
+
+            [[objects]]
+            type = "sphere"
+            center = [0.0, 0.0, 0.0]
+            radius = 0.5
+            material = "glass"
+
+            [[objects]]
+            type = "sphere"
+            center = [0.0, 2.0, 0.0]
+            radius = 0.25
+            material = "lamp"
+        "#).unwrap();
+
+        assert_eq!(scene.settings.width, 320);
+        assert_eq!(scene.settings.height, RenderSettings::default().height);
+        assert_eq!(scene.settings.samples_per_pixel, 8);
+        assert_eq!(scene.settings.sampler, crate::sampler::Sampler::BlueNoise);
+        assert_eq!(scene.camera.vfov, 45.0);
+        // Looking from +z at the origin is the default orientation
+        assert!((scene.camera.rotation - Matrix4::identity()).norm() < 1e-6);
+
+        let hitables = &scene.hitable_list.hitables;
+        assert_eq!(hitables.len(), 2);
+        assert_eq!(hitables[0].sphere.radius, 0.5);
+        assert!(!hitables[0].material.is_emissive());
+        assert_eq!(hitables[1].material.emission, Vector3::new(4.0, 2.0, 1.0));
+    }
+
+    #[test]
+    fn unknown_materials_and_fields_are_errors() {
+        let missing = parse(r#"
+            [[objects]]
+            type = "sphere"
+            center = [0.0, 0.0, 0.0]
+            radius = 1.0
+            material = "nope"
+        "#);
+        assert!(matches!(missing, Err(SceneError::Invalid(_))));
+
+        let nested = parse(r#"
+            [[objects]]
+            type = "mesh"
+            path = "scene.toml"
+        "#);
+        assert!(matches!(nested, Err(SceneError::Invalid(_))));
+
+        let typo = parse(r#"
+            [camera]
+            centre = [0.0, 0.0, 0.0]
+        "#);
+        assert!(matches!(typo, Err(SceneError::Toml(_))));
+    }
+
+    #[test]
+    fn look_at_points_the_view_direction() {
+        let camera = CameraDesc {
+            center: [1.0, 0.0, 0.0],
+            look_at: Some([1.0, 0.0, -5.0]),
+            up: up(),
+            vfov: vfov(),
+        };
+        let rotation = camera.to_camera().rotation;
+        let forward = rotation.transform_vector(&-Vector3::z());
+        assert!((forward - -Vector3::z()).norm() < 1e-6);
+
+        let camera = CameraDesc { look_at: Some([3.0, 0.0, 0.0]), ..camera };
+        let forward = camera.to_camera().rotation.transform_vector(&-Vector3::z());
+        assert!((forward - Vector3::x()).norm() < 1e-6);
+    }
+}
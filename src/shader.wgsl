@@ -1,140 +1,390 @@
-struct VertexInput {
-    @location(0) position: vec3<f32>,
-    @location(1) tex_coords: vec2<f32>,
-}
-
-struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
-    @location(0) tex_coords: vec2<f32>,
-}
-
-
 struct Camera {
     @location(0) aspect_ratio: f32,
     @location(1) image_width: u32,
     @location(2) image_height: f32,
-    @location(3) center: vec3<f32>,
-    @location(4) pixel00_loc: vec3<f32>,
-    @location(5) pixel_delta_u: vec3<f32>,
-    @location(6) pixel_delta_v: vec3<f32>,
-    @location(7) samples_per_pixel: u32,
-    @location(8) pixels_sample_scale: f32,
-    @location(9) max_depth: u32,
-    @location(10) iteration: u32,
-    @location(11) rotation: mat4x4<f32>,
-}
-
-@vertex
-fn vs_main(
-    model: VertexInput,
-) -> VertexOutput {
-    var out: VertexOutput;
-    out.tex_coords = model.tex_coords;
-    out.clip_position = vec4<f32>(model.position, 1.0);
-    return out;
+    @location(3) sampler_kind: u32,
+    @location(4) center: vec3<f32>,
+    @location(5) pixel00_loc: vec3<f32>,
+    @location(6) pixel_delta_u: vec3<f32>,
+    @location(7) pixel_delta_v: vec3<f32>,
+    @location(8) samples_per_pixel: u32,
+    @location(9) pixels_sample_scale: f32,
+    @location(10) max_depth: u32,
+    @location(11) iteration: u32,
+    @location(12) vfov: f32,
+    @location(13) rotation: mat4x4<f32>,
 }
 
 @group(0) @binding(0) var<uniform> camera: Camera;
+// 32 direction numbers for each of the two Sobol dimensions, four to an element
+@group(0) @binding(1) var<uniform> sobol_directions: array<vec4<u32>, 16>;
+@group(0) @binding(2) var blue_noise: texture_2d<f32>;
+
+// Bindings 0 to 4 of group 1, group 2 and group 3 depend on the
+// integrator, see compute.wgsl and downlevel.wgsl. Both provide
+// hitable_at, mesh_vertex_at, mesh_index_at, texel_at, light_count,
+// light_at, bvh_node_at and instance_at for the code below.
+@group(1) @binding(5) var<uniform> environment: Environment;
+@group(1) @binding(6) var environment_map: texture_2d<f32>;
+@group(1) @binding(7) var environment_marginal: texture_2d<f32>;
+@group(1) @binding(8) var environment_conditional: texture_2d<f32>;
+
+// Averages another iteration for the pixel into `prev_color`, the average of
+// the iterations before
+fn trace_pixel(pixel: vec2<u32>, prev_color: vec4<f32>) -> vec4<f32> {
+    let size = vec2<f32>(f32(camera.image_width), camera.image_height);
+    let tex_coords = (vec2<f32>(pixel) + 0.5) / size;
+
+    let x = tex_coords.x * size.x;
+    let y = tex_coords.y * size.y;
+
+    var sample_color = vec4<f32>(0.0);
+    for (var s = 0u; s < camera.samples_per_pixel; s = s + 1u) {
+        // Every sample of every iteration gets its own index, so no two draw the same numbers
+        sampler_start(pixel, (camera.iteration - 1u) * camera.samples_per_pixel + s);
+        let sample = sample_square();
+        let pixel_loc = camera.pixel00_loc + ((x + sample.x) * camera.pixel_delta_u) + ((y + sample.y) * camera.pixel_delta_v);
+
+        let ray_origin = camera.center;
+        var ray_direction = pixel_loc - ray_origin;
+        ray_direction = mat_4_to_3(camera.rotation) * ray_direction;
+        let ray = Ray(ray_origin, ray_direction);
+        sample_color = sample_color + ray_color(ray);
+    }
+    sample_color = sample_color * camera.pixels_sample_scale;
+    // The first iteration after a reset ignores whatever the history holds
+    if (camera.iteration <= 1u) {
+        return sample_color;
+    }
+    return (f32(camera.iteration - 1u) * prev_color + sample_color) / f32(camera.iteration);
+}
 
-@group(1) @binding(0) var<storage,read> hitabble_list: array<Hitable>;
+// Offset from the pixel center, within the pixel
+fn sample_square() -> vec2<f32> {
+    return sample_2d() - 0.5;
+}
 
-@group(2) @binding(0) var<storage,read_write> prev_frame: array<vec4<f32>>;
 
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+// Follows the path accumulating emitted light, weighted by the attenuation
+// of every bounce before it. Diffuse surfaces also sample a light or the
+// environment map directly, and emitters or sky found by the next bounce
+// are weighted against that with multiple importance sampling.
+fn ray_color(ray: Ray)  -> vec4<f32> {
+    var radiance = vec3<f32>(0.0, 0.0, 0.0);
+    var throughput = vec3<f32>(1.0, 1.0, 1.0);
+    var curr_ray = ray;
+    // Pdf of the bounce that produced curr_ray, zero when light sampling couldn't have
+    var bsdf_pdf = 0.0;
+    for(var depth = 0u; depth < camera.max_depth; depth = depth + 1u) {
+        let hit_record = get_hit_record(curr_ray, 0.001, max_f32);
+        if !hit_record.hit {
+            var weight = 1.0;
+            if bsdf_pdf > 0.0 && environment.enabled != 0u {
+                let direction = normalize(curr_ray.direction);
+                weight = power_heuristic(bsdf_pdf, environment_pdf(direction) / f32(light_choices()));
+            }
+            radiance += throughput * environment_color(curr_ray.direction) * weight;
+            break;
+        }
+        // Emitters shine from their geometric front, the side sample_light sees
+        if dot(curr_ray.direction, hit_record.face_normal) < 0.0 {
+            var weight = 1.0;
+            if bsdf_pdf > 0.0 && any(hit_record.material.emission > vec3<f32>(0.0)) {
+                weight = power_heuristic(bsdf_pdf, light_pdf(hit_record, curr_ray));
+            }
+            radiance += throughput * hit_record.material.emission * weight;
+        }
+        sampler_bounce(depth + 1u);
+        if hit_record.material.kind == LAMBERTIAN {
+            radiance += throughput * sample_light(hit_record);
+        }
+        let scatter_record = scatter(hit_record.material, curr_ray, hit_record);
+        if !scatter_record.hit {
+            // Absorbed, or a light that doesn't reflect
+            break;
+        }
+        throughput *= scatter_record.attenuation;
+        bsdf_pdf = scatter_record.pdf;
+        curr_ray = scatter_record.scattered;
+    }
+    return vec4<f32>(radiance, 1.0);
+}
 
-    let x = in.tex_coords.x * f32(camera.image_width);
-    let y = in.tex_coords.y * f32(camera.image_height);
-    
-    var seed = vec3<f32>(in.tex_coords, in.tex_coords.x * in.tex_coords.y);
-    seed = seed * f32(camera.iteration);
+// Lights sample_light picks from, an environment map counts as one more
+fn light_choices() -> u32 {
+    return light_count() + select(0u, 1u, environment.enabled != 0u);
+}
 
-    let u = u32(floor(x));
-    let v = u32(floor(y));
+// Next event estimation: the light reflected towards the path by one
+// uniformly picked light, MIS weighted against the diffuse bounce
+fn sample_light(rec: HitRecord) -> vec3<f32> {
+    let choices = light_choices();
+    if choices == 0u {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+    let count = f32(choices);
+    let index = min(u32(sample_1d() * count), choices - 1u);
+    let u = sample_2d();
+
+    var direction: vec3<f32>;
+    var distance: f32;
+    var pdf: f32; // solid angle
+    var emission: vec3<f32>;
+    if index == light_count() {
+        let sample = sample_environment_direction(u);
+        if sample.w <= 0.0 {
+            return vec3<f32>(0.0, 0.0, 0.0);
+        }
+        direction = sample.xyz;
+        distance = max_f32;
+        pdf = sample.w;
+        emission = environment_color(direction);
+    } else if light_at(index).kind == SPHERE_LIGHT {
+        let light = light_at(index);
+        // Uniform over the cone of directions the sphere covers
+        let to_center = light.p0 - rec.p;
+        let distance_squared = dot(to_center, to_center);
+        let sin2_max = light.radius * light.radius / distance_squared;
+        if sin2_max >= 1.0 {
+            return vec3<f32>(0.0, 0.0, 0.0);
+        }
+        let one_minus_cos_max = sin2_max / (1.0 + sqrt(1.0 - sin2_max));
+        let cos_theta = 1.0 - u.x * one_minus_cos_max;
+        let sin_theta = sqrt(max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi = 2.0 * pi * u.y;
+        direction = onb(to_center / sqrt(distance_squared)) * vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+        let b = dot(to_center, direction);
+        distance = b - sqrt(max(0.0, light.radius * light.radius - (distance_squared - b * b)));
+        pdf = 1.0 / (2.0 * pi * one_minus_cos_max);
+        emission = light.emission;
+    } else {
+        // Uniform over the triangle's area
+        let light = light_at(index);
+        let su = sqrt(u.x);
+        let point = (1.0 - su) * light.p0 + su * (1.0 - u.y) * light.p1 + su * u.y * light.p2;
+        let to_light = point - rec.p;
+        let distance_squared = dot(to_light, to_light);
+        distance = sqrt(distance_squared);
+        direction = to_light / distance;
+        let cos_light = -dot(normalize(cross(light.p1 - light.p0, light.p2 - light.p0)), direction);
+        if cos_light <= 0.0 {
+            return vec3<f32>(0.0, 0.0, 0.0);
+        }
+        pdf = distance_squared / (cos_light * light.area);
+        emission = light.emission;
+    }
 
-    let prev_color = prev_frame[u + v * camera.image_width];
+    let cos_surface = dot(rec.normal, direction);
+    if cos_surface <= 0.0 {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+    if get_hit_record(Ray(rec.p, direction), 0.001, distance * 0.999).hit {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+    let light_pdf = pdf / count;
+    let brdf = rec.material.albedo / pi;
+    let weight = power_heuristic(light_pdf, cos_surface / pi);
+    return brdf * cos_surface * emission * weight / light_pdf;
+}
 
-    let sample = sample_square(seed);
-    let pixel_loc = camera.pixel00_loc + ((x + sample.x) * camera.pixel_delta_u) + ((y + sample.y) * camera.pixel_delta_v);
+// Solid angle pdf of sample_light choosing the point r hit on an emitter
+fn light_pdf(rec: HitRecord, r: Ray) -> f32 {
+    let count = f32(light_choices());
+    if rec.shape == SPHERE {
+        // Front faces have outward normals
+        let to_center = rec.p - rec.size * rec.normal - r.origin;
+        let sin2_max = rec.size * rec.size / dot(to_center, to_center);
+        if sin2_max >= 1.0 {
+            return 0.0;
+        }
+        let one_minus_cos_max = sin2_max / (1.0 + sqrt(1.0 - sin2_max));
+        return 1.0 / (2.0 * pi * one_minus_cos_max * count);
+    }
+    // Same density as sample_light, so the triangle's normal, not the shading one
+    let distance = rec.t * length(r.direction);
+    let cos_light = abs(dot(rec.face_normal, normalize(r.direction)));
+    return distance * distance / (cos_light * rec.size * count);
+}
 
-    let ray_origin = camera.center;
-    var ray_direction = pixel_loc - ray_origin;
-    ray_direction = mat_4_to_3(camera.rotation) * ray_direction;
-    let ray = Ray(ray_origin, ray_direction);
-    let sample_color = ray_color(ray, seed);
-    let color = (f32(camera.iteration - 1u) * prev_color + sample_color) / f32(camera.iteration);
-    prev_frame[u + v * camera.image_width] = color;
-    return color;
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    return a / (a + other_pdf * other_pdf);
 }
 
-fn sample_vec3(rng_seed: vec3<f32>) -> vec3<f32> {
-    let out = vec3<f32>(
-        random_vec3(rng_seed + vec3<f32>(0.0, 1.0, 2.0)),
-        random_vec3(rng_seed + vec3<f32>(3.0, 4.0, 5.0)),
-        random_vec3(rng_seed + vec3<f32>(6.0, 7.0, 8.0))
-    );
-    return out * 2.0 - 1.0;
+fn environment_color(direction: vec3<f32>) -> vec3<f32> {
+    let unit_direction = normalize(direction);
+    if environment.enabled == 0u {
+        let a = 0.5*(unit_direction.y + 1.0);
+        return (1.0-a)*vec3(1.0, 1.0, 1.0) + a*vec3(0.5, 0.7, 1.0);
+    }
+    return environment.intensity * sample_environment(environment_uv(unit_direction));
 }
 
-fn sample_square(rng_seed: vec3<f32>) -> vec2<f32> {
-    let sample = vec2<f32>(
-        random_vec3(rng_seed + vec3<f32>(0.0, 1.0, 2.0)),
-        random_vec3(rng_seed + vec3<f32>(3.0, 4.0, 5.0))
-    );
-    return sample - 0.5;
+// Equirectangular coordinates of a world space direction, v = 0 looks up
+fn environment_uv(direction: vec3<f32>) -> vec2<f32> {
+    let c = cos(environment.rotation);
+    let s = sin(environment.rotation);
+    // Undo the map's rotation about +y
+    let d = vec3<f32>(c * direction.x - s * direction.z, direction.y, s * direction.x + c * direction.z);
+    let u = atan2(d.x, -d.z) / (2.0 * pi) + 0.5;
+    let v = acos(clamp(d.y, -1.0, 1.0)) / pi;
+    return vec2<f32>(u, v);
 }
 
+// Inverse of environment_uv
+fn environment_direction(uv: vec2<f32>) -> vec3<f32> {
+    let theta = uv.y * pi;
+    let phi = (uv.x - 0.5) * 2.0 * pi;
+    let d = vec3<f32>(sin(theta) * sin(phi), cos(theta), -sin(theta) * cos(phi));
+    let c = cos(environment.rotation);
+    let s = sin(environment.rotation);
+    return vec3<f32>(c * d.x + s * d.z, d.y, -s * d.x + c * d.z);
+}
 
-fn ray_color(ray: Ray, seed: vec3<f32>)  -> vec4<f32> {
-    var hits = 0u;
-    var attenuations = array<vec3<f32>, 100>();
-    var curr_ray = ray;
-    var mutable_seed = seed;
-    for(var depth = 0u; depth < camera.max_depth; depth = depth + 1u) {
-        let hit_record = get_hit_record(curr_ray, 0.001, max_f32);
-        if hit_record.hit {
-            let scatter_record = scatter(hit_record.material, curr_ray, hit_record, mutable_seed);
-            if scatter_record.hit {
-                attenuations[hits] = scatter_record.attenuation;
-                curr_ray = scatter_record.scattered;
-                hits = hits + 1u;
-                mutable_seed = sample_vec3(mutable_seed);
-            }
-            else {
-                break;
-            }
-        }
-        else {
-            break;
+// Picks a direction proportionally to the map's luminance using the CDFs
+// built on the CPU. Returns the direction with its solid angle pdf in w.
+fn sample_environment_direction(u: vec2<f32>) -> vec4<f32> {
+    let size = vec2<i32>(textureDimensions(environment_conditional));
+    let y = search_cdf(environment_marginal, 0, size.y, u.x);
+    let x = search_cdf(environment_conditional, y, size.x, u.y);
+    let y_cdf = cdf_step(environment_marginal, 0, y);
+    let x_cdf = cdf_step(environment_conditional, y, x);
+    // Where u falls inside the step places the sample inside the pixel
+    let dy = clamp((u.x - y_cdf.x) / max(y_cdf.y - y_cdf.x, 1e-12), 0.0, 1.0);
+    let dx = clamp((u.y - x_cdf.x) / max(x_cdf.y - x_cdf.x, 1e-12), 0.0, 1.0);
+    let uv = vec2<f32>((f32(x) + dx) / f32(size.x), (f32(y) + dy) / f32(size.y));
+    let sin_theta = sin(uv.y * pi);
+    if sin_theta <= 0.0 {
+        return vec4<f32>(0.0, 0.0, 0.0, 0.0);
+    }
+    let pdf_uv = (y_cdf.y - y_cdf.x) * f32(size.y) * (x_cdf.y - x_cdf.x) * f32(size.x);
+    return vec4<f32>(environment_direction(uv), pdf_uv / (2.0 * pi * pi * sin_theta));
+}
+
+// Solid angle pdf of sample_environment_direction
+fn environment_pdf(direction: vec3<f32>) -> f32 {
+    let uv = environment_uv(direction);
+    let sin_theta = sin(uv.y * pi);
+    if sin_theta <= 0.0 {
+        return 0.0;
+    }
+    let size = vec2<i32>(textureDimensions(environment_conditional));
+    let x = clamp(i32(uv.x * f32(size.x)), 0, size.x - 1);
+    let y = clamp(i32(uv.y * f32(size.y)), 0, size.y - 1);
+    let y_cdf = cdf_step(environment_marginal, 0, y);
+    let x_cdf = cdf_step(environment_conditional, y, x);
+    let pdf_uv = (y_cdf.y - y_cdf.x) * f32(size.y) * (x_cdf.y - x_cdf.x) * f32(size.x);
+    return pdf_uv / (2.0 * pi * pi * sin_theta);
+}
+
+// First index in the row whose CDF value is above u
+fn search_cdf(cdf: texture_2d<f32>, row: i32, count: i32, u: f32) -> i32 {
+    var low = 0;
+    var high = count - 1;
+    while low < high {
+        let middle = (low + high) / 2;
+        if textureLoad(cdf, vec2<i32>(middle, row), 0).r > u {
+            high = middle;
+        } else {
+            low = middle + 1;
         }
     }
-    let unit_direction = normalize(curr_ray.direction);
-    let a = 0.5*(unit_direction.y + 1.0);
-    var color =  (1.0-a)*vec3(1.0, 1.0, 1.0) + a*vec3(0.5, 0.7, 1.0);
-    for (var i = 0u; i < hits; i = i + 1u) {
-        color *= attenuations[i];
+    return low;
+}
+
+// The CDF before and after step i
+fn cdf_step(cdf: texture_2d<f32>, row: i32, i: i32) -> vec2<f32> {
+    var before = 0.0;
+    if i > 0 {
+        before = textureLoad(cdf, vec2<i32>(i - 1, row), 0).r;
     }
-    return vec4<f32>(color, 1.0);
+    return vec2<f32>(before, textureLoad(cdf, vec2<i32>(i, row), 0).r);
+}
+
+// Bilinear, wrapping around horizontally and clamped at the poles
+fn sample_environment(uv: vec2<f32>) -> vec3<f32> {
+    let size = vec2<i32>(textureDimensions(environment_map));
+    let p = uv * vec2<f32>(size) - 0.5;
+    let base = floor(p);
+    let f = p - base;
+    let x0 = ((i32(base.x) % size.x) + size.x) % size.x;
+    let x1 = (x0 + 1) % size.x;
+    let y0 = clamp(i32(base.y), 0, size.y - 1);
+    let y1 = clamp(i32(base.y) + 1, 0, size.y - 1);
+    let top = mix(textureLoad(environment_map, vec2(x0, y0), 0).rgb, textureLoad(environment_map, vec2(x1, y0), 0).rgb, f.x);
+    let bottom = mix(textureLoad(environment_map, vec2(x0, y1), 0).rgb, textureLoad(environment_map, vec2(x1, y1), 0).rgb, f.x);
+    return mix(top, bottom, f.y);
 }
 
 fn get_hit_record(r: Ray, t_min: f32, t_max: f32) -> HitRecord {
-    var closest_so_far = max_f32;
+    var closest_so_far = t_max;
     var record = null_hit_record();
-    for (var idx = 0u; idx < arrayLength(&hitabble_list); idx = idx + 1u) {
-        let sphere = hitabble_list[idx];
-        let temp_record = hit_object(sphere, r, t_min, closest_so_far);
-        if temp_record.hit {
-            closest_so_far = temp_record.t;
-            record = temp_record;
+    let inv_direction = 1.0 / r.direction;
+    if hit_aabb(bvh_node_at(0u), r, inv_direction, t_min, closest_so_far) < 0.0 {
+        return record;
+    }
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 1u;
+    stack[0] = 0u;
+    while stack_size > 0u {
+        stack_size = stack_size - 1u;
+        let node = bvh_node_at(stack[stack_size]);
+        if node.count > 0u {
+            for (var idx = node.left_first; idx < node.left_first + node.count; idx = idx + 1u) {
+                let temp_record = hit_object(hitable_at(idx), r, t_min, closest_so_far);
+                if temp_record.hit {
+                    closest_so_far = temp_record.t;
+                    record = temp_record;
+                }
+            }
+            continue;
+        }
+        let left = node.left_first;
+        let right = left + 1u;
+        let left_t = hit_aabb(bvh_node_at(left), r, inv_direction, t_min, closest_so_far);
+        let right_t = hit_aabb(bvh_node_at(right), r, inv_direction, t_min, closest_so_far);
+        // Push the farther child first so the nearer one is visited next
+        if left_t >= 0.0 && right_t >= 0.0 {
+            if left_t < right_t {
+                stack[stack_size] = right;
+                stack[stack_size + 1u] = left;
+            } else {
+                stack[stack_size] = left;
+                stack[stack_size + 1u] = right;
+            }
+            stack_size = stack_size + 2u;
+        } else if left_t >= 0.0 {
+            stack[stack_size] = left;
+            stack_size = stack_size + 1u;
+        } else if right_t >= 0.0 {
+            stack[stack_size] = right;
+            stack_size = stack_size + 1u;
         }
     }
     return record;
 }
 
+// Slab test, returns the entry distance or -1.0 when the box is missed
+fn hit_aabb(node: BvhNode, r: Ray, inv_direction: vec3<f32>, t_min: f32, t_max: f32) -> f32 {
+    let t0 = (node.min - r.origin) * inv_direction;
+    let t1 = (node.max - r.origin) * inv_direction;
+    let near = min(t0, t1);
+    let far = max(t0, t1);
+    let t_near = max(max(near.x, near.y), max(near.z, t_min));
+    let t_far = min(min(far.x, far.y), min(far.z, t_max));
+    if t_near <= t_far {
+        return t_near;
+    }
+    return -1.0;
+}
+
 fn hit_object(hitable: Hitable, r: Ray, t_min: f32, t_max: f32) -> HitRecord {
     if hitable.kind == SPHERE {
         return hit_sphere(hitable, r, t_min, t_max);
     }
+    if hitable.kind == MESH {
+        return hit_instance(hitable, r, t_min, t_max);
+    }
     return null_hit_record();
 }
 
@@ -165,42 +415,216 @@ fn hit_sphere(hitable: Hitable, r: Ray, ray_tmin: f32, ray_tmax: f32) -> HitReco
 
     let p = at(r,root);
     let normal = normalize((p - hitable.sphere.center) / hitable.sphere.radius);
-    var record = HitRecord(true,root,p,normal, hitable.material);
-    record.normal = set_front_face(record, r);
-    return record;
+    let uv = vec2<f32>(atan2(-normal.z, normal.x) / (2.0 * pi) + 0.5, acos(-normal.y) / pi);
+    let record = HitRecord(true, root, p, normal, true, apply_texture(hitable.material, uv), SPHERE, hitable.sphere.radius, normal);
+    return set_front_face(record, r);
 
 }
 
-fn set_front_face(rec: HitRecord, r: Ray) -> vec3<f32> {
-    let front_face = dot(r.direction, rec.normal) < 0.0;
-    if !front_face {
-        return -rec.normal;
+// Möller–Trumbore intersection, returns (t, b1, b2) where b1 and b2 are the
+// barycentric weights of p1 and p2. t is negative when the ray misses.
+fn intersect_triangle(r: Ray, p0: vec3<f32>, p1: vec3<f32>, p2: vec3<f32>) -> vec3<f32> {
+    let miss = vec3<f32>(-1.0, 0.0, 0.0);
+    let edge1 = p1 - p0;
+    let edge2 = p2 - p0;
+    let pvec = cross(r.direction, edge2);
+    let det = dot(edge1, pvec);
+    if abs(det) < 1e-8 {
+        return miss;
+    }
+    let inv_det = 1.0 / det;
+    let tvec = r.origin - p0;
+    let b1 = dot(tvec, pvec) * inv_det;
+    if b1 < 0.0 || b1 > 1.0 {
+        return miss;
     }
-    return rec.normal;
+    let qvec = cross(tvec, edge1);
+    let b2 = dot(r.direction, qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return miss;
+    }
+    return vec3<f32>(dot(edge2, qvec) * inv_det, b1, b2);
 }
 
-fn scatter(material: Material, r: Ray, rec: HitRecord, seed: vec3<f32>) -> ScatterRecord {
+// Intersects the mesh's bottom level BVH in object space. The ray direction
+// isn't normalized after the transform, so t is the same in both spaces.
+fn hit_instance(hitable: Hitable, r: Ray, ray_tmin: f32, ray_tmax: f32) -> HitRecord {
+    let instance = instance_at(hitable.instance);
+    let object_ray = Ray(
+        (instance.world_to_object * vec4<f32>(r.origin, 1.0)).xyz,
+        (instance.world_to_object * vec4<f32>(r.direction, 0.0)).xyz
+    );
+    let hit = hit_blas(instance.blas_root, object_ray, ray_tmin, ray_tmax);
+    if !hit.hit {
+        return null_hit_record();
+    }
+
+    let base = hit.triangle * 3u;
+    let v0 = mesh_vertex_at(mesh_index_at(base));
+    let v1 = mesh_vertex_at(mesh_index_at(base + 1u));
+    let v2 = mesh_vertex_at(mesh_index_at(base + 2u));
+    let b0 = 1.0 - hit.b1 - hit.b2;
+    let edge1 = v1.position - v0.position;
+    let edge2 = v2.position - v0.position;
+    var normal = cross(edge1, edge2);
+    let smooth_normal = b0 * v0.normal + hit.b1 * v1.normal + hit.b2 * v2.normal;
+    if dot(smooth_normal, smooth_normal) > 0.0 {
+        normal = smooth_normal;
+    }
+    // Normals go back to world space with the inverse transpose
+    normal = normalize(transpose(mat_4_to_3(instance.world_to_object)) * normal);
+    let uv = b0 * vec2(v0.u, v0.v) + hit.b1 * vec2(v1.u, v1.v) + hit.b2 * vec2(v2.u, v2.v);
+    let object_to_world = mat_4_to_3(instance.object_to_world);
+    let face = cross(object_to_world * edge1, object_to_world * edge2);
+    let area = length(face) / 2.0;
+    let record = HitRecord(true, hit.t, at(r, hit.t), normal, true, apply_texture(hitable.material, uv), MESH, area, normalize(face));
+    return set_front_face(record, r);
+}
+
+fn hit_blas(root: u32, r: Ray, t_min: f32, t_max: f32) -> TriangleHit {
+    var closest = TriangleHit(false, t_max, 0.0, 0.0, 0u);
+    let inv_direction = 1.0 / r.direction;
+    if hit_aabb(bvh_node_at(root), r, inv_direction, t_min, t_max) < 0.0 {
+        return closest;
+    }
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 1u;
+    stack[0] = root;
+    while stack_size > 0u {
+        stack_size = stack_size - 1u;
+        let node = bvh_node_at(stack[stack_size]);
+        if node.count > 0u {
+            for (var tri = node.left_first; tri < node.left_first + node.count; tri = tri + 1u) {
+                let base = tri * 3u;
+                let hit = intersect_triangle(
+                    r,
+                    mesh_vertex_at(mesh_index_at(base)).position,
+                    mesh_vertex_at(mesh_index_at(base + 1u)).position,
+                    mesh_vertex_at(mesh_index_at(base + 2u)).position
+                );
+                if t_min < hit.x && hit.x < closest.t {
+                    closest = TriangleHit(true, hit.x, hit.y, hit.z, tri);
+                }
+            }
+            continue;
+        }
+        let left = node.left_first;
+        let right = left + 1u;
+        let left_t = hit_aabb(bvh_node_at(left), r, inv_direction, t_min, closest.t);
+        let right_t = hit_aabb(bvh_node_at(right), r, inv_direction, t_min, closest.t);
+        if left_t >= 0.0 && right_t >= 0.0 {
+            if left_t < right_t {
+                stack[stack_size] = right;
+                stack[stack_size + 1u] = left;
+            } else {
+                stack[stack_size] = left;
+                stack[stack_size + 1u] = right;
+            }
+            stack_size = stack_size + 2u;
+        } else if left_t >= 0.0 {
+            stack[stack_size] = left;
+            stack_size = stack_size + 1u;
+        } else if right_t >= 0.0 {
+            stack[stack_size] = right;
+            stack_size = stack_size + 1u;
+        }
+    }
+    return closest;
+}
+
+// Bakes the texture color at uv into the material's albedo
+fn apply_texture(material: Material, uv: vec2<f32>) -> Material {
+    if material.texture.width == 0u {
+        return material;
+    }
+    // Bilinear filtering with repeat wrapping, texel centers at half-integers
+    let p = uv * vec2<f32>(f32(material.texture.width), f32(material.texture.height)) - 0.5;
+    let base = floor(p);
+    let f = p - base;
+    let x = i32(base.x);
+    let y = i32(base.y);
+    let top = mix(load_texel(material.texture, x, y), load_texel(material.texture, x + 1, y), f.x);
+    let bottom = mix(load_texel(material.texture, x, y + 1), load_texel(material.texture, x + 1, y + 1), f.x);
+    var textured = material;
+    textured.albedo = material.albedo * mix(top, bottom, f.y);
+    return textured;
+}
+
+fn load_texel(texture: Texture, x: i32, y: i32) -> vec3<f32> {
+    let w = i32(texture.width);
+    let h = i32(texture.height);
+    let wx = ((x % w) + w) % w;
+    let wy = ((y % h) + h) % h;
+    let srgb = unpack4x8unorm(texel_at(texture.offset + u32(wy * w + wx))).rgb;
+    return select(pow((srgb + 0.055) / 1.055, vec3(2.4)), srgb / 12.92, srgb <= vec3(0.04045));
+}
+
+// Records which side was hit and makes the normal face against the ray
+fn set_front_face(rec: HitRecord, r: Ray) -> HitRecord {
+    var record = rec;
+    record.front_face = dot(r.direction, rec.normal) < 0.0;
+    if !record.front_face {
+        record.normal = -rec.normal;
+    }
+    return record;
+}
+
+fn scatter(material: Material, r: Ray, rec: HitRecord) -> ScatterRecord {
     if material.kind == LAMBERTIAN {
-        return scatter_lambertian(material, r, rec, seed);
+        return scatter_lambertian(material, r, rec);
     }
     if material.kind == METAL {
-        return scatter_metal(material, r, rec, seed);
+        return scatter_metal(material, r, rec);
+    }
+    if material.kind == DIELECTRIC {
+        return scatter_dielectric(material, r, rec);
     }
-    return ScatterRecord(false, vec3(0.0, 0.0, 0.0), Ray(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0)));
+    // EMISSIVE only emits
+    return ScatterRecord(false, vec3(0.0, 0.0, 0.0), Ray(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0)), 0.0);
 }
 
-fn scatter_lambertian(material: Material, r: Ray, rec: HitRecord, seed: vec3<f32>) -> ScatterRecord {
-    let scatter_ray = random_vec3_on_hemisphere(rec.normal, seed);
+// Cosine weighted, so the cosine and pdf cancel and only the albedo is left
+fn scatter_lambertian(material: Material, r: Ray, rec: HitRecord) -> ScatterRecord {
+    let scatter_ray = random_cosine_direction(rec.normal);
     let scattered = Ray(rec.p, scatter_ray);
     let attenuation = material.albedo;
-    return ScatterRecord(true, attenuation, scattered);
+    let pdf = max(dot(scatter_ray, rec.normal), 0.0) / pi;
+    return ScatterRecord(pdf > 0.0, attenuation, scattered, pdf);
 }
 
-fn scatter_metal(material: Material, r: Ray, rec: HitRecord, seed: vec3<f32>) -> ScatterRecord {
-    let reflected = reflect(normalize(r.direction), rec.normal);
+fn scatter_metal(material: Material, r: Ray, rec: HitRecord) -> ScatterRecord {
+    var reflected = reflect(normalize(r.direction), rec.normal);
+    reflected = reflected + material.roughness * random_unit_vector();
     let scattered = Ray(rec.p, reflected);
     let attenuation = material.albedo;
-    return ScatterRecord(true, attenuation, scattered);
+    // Fuzzed reflections that end up below the surface are absorbed
+    return ScatterRecord(dot(reflected, rec.normal) > 0.0, attenuation, scattered, 0.0);
+}
+
+// Refracts through the surface, or reflects on total internal reflection and
+// with the probability given by Schlick's approximation of Fresnel
+fn scatter_dielectric(material: Material, r: Ray, rec: HitRecord) -> ScatterRecord {
+    var ri = material.ior;
+    if rec.front_face {
+        ri = 1.0 / material.ior;
+    }
+    let unit_direction = normalize(r.direction);
+    let cos_theta = min(dot(-unit_direction, rec.normal), 1.0);
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let cannot_refract = ri * sin_theta > 1.0;
+    var direction: vec3<f32>;
+    if cannot_refract || reflectance(cos_theta, ri) > sample_1d() {
+        direction = reflect(unit_direction, rec.normal);
+    } else {
+        direction = refract(unit_direction, rec.normal, ri);
+    }
+    return ScatterRecord(true, material.albedo, Ray(rec.p, direction), 0.0);
+}
+
+fn reflectance(cosine: f32, refraction_index: f32) -> f32 {
+    var r0 = (1.0 - refraction_index) / (1.0 + refraction_index);
+    r0 = r0 * r0;
+    return r0 + (1.0 - r0) * pow(1.0 - cosine, 5.0);
 }
 
 fn reflect(v: vec3<f32>, n: vec3<f32>) -> vec3<f32> {
@@ -208,7 +632,7 @@ fn reflect(v: vec3<f32>, n: vec3<f32>) -> vec3<f32> {
 }
 
 fn null_hit_record() -> HitRecord {
-    return HitRecord(false, 0.0, vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0), Material(vec3(0.0, 0.0, 0.0), 0));
+    return HitRecord(false, 0.0, vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0), false, Material(vec3(0.0, 0.0, 0.0), 0, Texture(0u, 0u, 0u), 1.0, vec3(0.0, 0.0, 0.0), 0.0), SPHERE, 0.0, vec3(0.0, 0.0, 0.0));
 }
 
 struct Ray {
@@ -217,15 +641,69 @@ struct Ray {
 }
 
 const SPHERE = u32(0);
+const MESH = u32(1);
+const pi = 3.14159265358979;
 // const max_f32 = 3.40282347e+38;
 const max_f32 = 1000000.0;
 
 struct Hitable {
     kind: u32,
+    instance: u32,
     sphere: Sphere,
     material: Material,
 }
 
+struct Instance {
+    object_to_world: mat4x4<f32>,
+    world_to_object: mat4x4<f32>,
+    blas_root: u32,
+}
+
+struct TriangleHit {
+    hit: bool,
+    t: f32,
+    b1: f32,
+    b2: f32,
+    triangle: u32,
+}
+
+// Interior nodes have count == 0 and children at left_first and left_first + 1,
+// leaves cover the hitables left_first..left_first + count
+struct BvhNode {
+    min: vec3<f32>,
+    left_first: u32,
+    max: vec3<f32>,
+    count: u32,
+}
+
+const BVH_STACK_SIZE = 64u;
+
+struct Environment {
+    rotation: f32, // radians about +y
+    intensity: f32,
+    enabled: u32, // zero falls back to the gradient sky
+}
+
+struct Light {
+    p0: vec3<f32>, // sphere center, or the first corner of the triangle
+    kind: u32,
+    p1: vec3<f32>,
+    radius: f32,
+    p2: vec3<f32>,
+    area: f32,
+    emission: vec3<f32>,
+}
+
+const SPHERE_LIGHT = u32(0);
+const TRIANGLE_LIGHT = u32(1);
+
+struct MeshVertex {
+    position: vec3<f32>,
+    u: f32,
+    normal: vec3<f32>,
+    v: f32,
+}
+
 struct Sphere {
     center: vec3<f32>,
     radius: f32,
@@ -236,57 +714,209 @@ struct HitRecord {
     t: f32,
     p: vec3<f32>,
     normal: vec3<f32>,
+    front_face: bool,
     material: Material,
+    shape: u32, // SPHERE or MESH
+    size: f32, // sphere radius, or world space area of the hit triangle
+    face_normal: vec3<f32>, // geometric normal, never interpolated or flipped
 }
 
 struct ScatterRecord {
     hit: bool,
     attenuation: vec3<f32>,
     scattered: Ray,
+    pdf: f32, // solid angle, zero for specular bounces
 }
 
 struct Material {
     albedo: vec3<f32>,
     kind: u32,
+    texture: Texture,
+    ior: f32, // index of refraction, dielectrics only
+    emission: vec3<f32>, // radiance emitted by front faces
+    roughness: f32, // fuzz added to metal reflections
+}
+
+struct Texture {
+    offset: u32,
+    width: u32,
+    height: u32,
 }
 
 const LAMBERTIAN = u32(0);
 const METAL = u32(1);
+const DIELECTRIC = u32(2);
+const EMISSIVE = u32(3);
+
+// The warps below are mirrored by sampling.rs, whose tests check them
+
+// Uniformly distributed direction on the unit sphere
+fn random_unit_vector() -> vec3<f32> {
+    let u = sample_2d();
+    let z = 1.0 - 2.0 * u.x;
+    let phi = 2.0 * pi * u.y;
+    let r = sqrt(max(0.0, 1.0 - z * z));
+    return vec3<f32>(r * cos(phi), r * sin(phi), z);
+}
+
+// Direction about the normal with pdf cos(theta) / pi
+fn random_cosine_direction(normal: vec3<f32>) -> vec3<f32> {
+    let u = sample_2d();
+    let r1 = u.x;
+    let r2 = u.y;
+    let phi = 2.0 * pi * r1;
+    let local = vec3<f32>(cos(phi) * sqrt(r2), sin(phi) * sqrt(r2), sqrt(1.0 - r2));
+    return onb(normal) * local;
+}
+
+// Orthonormal basis with n as the z axis (Duff et al. 2017)
+fn onb(n: vec3<f32>) -> mat3x3<f32> {
+    let s = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    return mat3x3<f32>(
+        vec3<f32>(1.0 + s * n.x * n.x * a, s * b, -s * n.x),
+        vec3<f32>(b, s + n.y * n.y * a, -n.y),
+        n
+    );
+}
+
+
+const RANDOM_SAMPLER = 0u;
+const SOBOL_SAMPLER = 1u;
+const BLUE_NOISE_SAMPLER = 2u;
+
+// Draws a bounce may take: the light, the point on it and the new direction
+const DIMENSIONS_PER_BOUNCE = 3u;
 
-fn random_vec3_on_hemisphere(normal: vec3<f32>, rng_seed: vec3<f32>) -> vec3<f32> {
-    let p = normal + sample_vec3(rng_seed);
-    var normed = normalize(p);
-    if dot(normed, normal) < 0.0 {
-        normed = -normed;
+// The sample being traced and the next of its dimensions, each draw taking
+// one whether it uses one number or two
+var<private> sampler_pixel: vec2<u32>;
+var<private> sampler_sample: u32;
+var<private> sampler_dimension: u32;
+
+// Starts one sample of a pixel at the camera, whose jitter is the first draw
+fn sampler_start(pixel: vec2<u32>, sample: u32) {
+    sampler_pixel = pixel;
+    sampler_sample = sample;
+    sampler_bounce(0u);
+}
+
+// Moves on to the draws of a bounce, the same dimensions for every sample
+// however many the bounces before took
+fn sampler_bounce(bounce: u32) {
+    sampler_dimension = bounce * DIMENSIONS_PER_BOUNCE;
+    rng_seed(sampler_pixel_index(), sampler_sample, bounce);
+}
+
+fn sampler_pixel_index() -> u32 {
+    return sampler_pixel.y * camera.image_width + sampler_pixel.x;
+}
+
+fn sample_1d() -> f32 {
+    return sample_2d().x;
+}
+
+// The next draw in [0, 1)^2 from the camera's sampler
+fn sample_2d() -> vec2<f32> {
+    let dimension = sampler_dimension;
+    sampler_dimension += 1u;
+    if camera.sampler_kind == SOBOL_SAMPLER {
+        return sobol_2d(dimension);
+    }
+    if camera.sampler_kind == BLUE_NOISE_SAMPLER {
+        return blue_noise_2d(dimension);
     }
-    return normed;
+    return vec2<f32>(rng_next_f32(), rng_next_f32());
 }
 
+// Owen scrambled Sobol point, its samples shuffled and its bits scrambled
+// differently for every pixel and dimension (Burley 2020). Mirrored by
+// sampler.rs.
+fn sobol_2d(dimension: u32) -> vec2<f32> {
+    let seed = pcg_hash(sampler_pixel_index() + pcg_hash(dimension));
+    let index = nested_uniform_scramble(sampler_sample, seed);
+    return vec2<f32>(
+        unit_float(nested_uniform_scramble(sobol(index, 0u), pcg_hash(seed))),
+        unit_float(nested_uniform_scramble(sobol(index, 1u), pcg_hash(seed + 1u))),
+    );
+}
+
+fn sobol(index: u32, dimension: u32) -> u32 {
+    var x = 0u;
+    var bits = index;
+    for (var bit = dimension * 32u; bits != 0u; bit += 1u) {
+        if (bits & 1u) != 0u {
+            x ^= sobol_directions[bit / 4u][bit % 4u];
+        }
+        bits >>= 1u;
+    }
+    return x;
+}
 
-fn random_vec2(v: vec2<f32>) -> f32 { return float_construct(hash_vec2(vec2<u32>(bitcast<u32>(v.x), bitcast<u32>(v.y)))); }
-fn random_vec3(v: vec3<f32>) -> f32 { return float_construct(hash_vec3(vec3<u32>(bitcast<u32>(v.x), bitcast<u32>(v.y), bitcast<u32>(v.z)))); }
-fn hash(x: u32) -> u32 {
-    var result = x;
-    result += (result << 10u);
-    result ^= (result >>  6u);
-    result += (result <<  3u);
-    result ^= (result >> 11u);
-    result += (result << 15u);
-    return result;
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    var v = reverseBits(x) + seed;
+    v ^= v * 0x6c50b47cu;
+    v ^= v * 0xb82f1e52u;
+    v ^= v * 0xc7afe638u;
+    v ^= v * 0x8d22f6e6u;
+    return reverseBits(v);
 }
 
-// Compound versions of the hashing algorithm
-fn hash_vec2(v: vec2<u32>) -> u32 { return hash(v.x ^ hash(v.y)); }
-fn hash_vec3(v: vec3<u32>) -> u32 { return hash((v.x ^ hash(v.y)) ^ hash(v.z)); }
+// The R2 sequence, whose points fill the square evenly in any run, as
+// 32 bit fractions (Roberts 2018)
+fn r2(index: u32) -> vec2<u32> {
+    return vec2<u32>(index * 3242174889u, index * 2447445414u);
+}
 
-// Construct a float with half-open range [0:1] using low 23 bits.
-fn float_construct(m: u32) -> f32 {
-    let ieee_mantissa: u32 = 0x007FFFFFu; // binary32 mantissa bitmask
-    let ieee_one: u32      = 0x3F800000u; // 1.0 in IEEE binary32
+// The R2 sequence rotated by blue noise, so neighbouring pixels start it at
+// very different points. Each dimension reads the tile at its own offset.
+fn blue_noise_2d(dimension: u32) -> vec2<f32> {
+    let size = textureDimensions(blue_noise);
+    let offset = r2(dimension + 1u) >> vec2<u32>(24u);
+    let texel = (sampler_pixel + offset) % size;
+    let noise = vec2<f32>(
+        textureLoad(blue_noise, texel, 0).x,
+        textureLoad(blue_noise, (texel + size / 2u) % size, 0).x,
+    );
+    let rotation = vec2<u32>(noise * 16777216.0) << vec2<u32>(8u);
+    let point = r2(sampler_sample) + rotation;
+    return vec2<f32>(unit_float(point.x), unit_float(point.y));
+}
 
-    let result = (m & ieee_mantissa) | ieee_one;  // Keep only mantissa bits and add exponent
+// Uniform in [0, 1) from the top 24 bits of a 32 bit fraction
+fn unit_float(x: u32) -> f32 {
+    return f32(x >> 8u) * (1.0 / 16777216.0);
+}
+
+// Generator state of this invocation, reseeded for every pixel sample and
+// bounce. Mirrored by rng.rs, keep the two in step.
+var<private> rng_state: u32;
+
+// The stream for one bounce of one sample of a pixel
+fn rng_seed(pixel: u32, sample: u32, bounce: u32) {
+    rng_state = pcg_hash(pixel + pcg_hash(sample + pcg_hash(bounce)));
+}
+
+// PCG with 32 bits of state: an LCG step, permuted by RXS M XS (O'Neill 2014)
+fn rng_next_u32() -> u32 {
+    rng_state = rng_state * 747796405u + 2891336453u;
+    return pcg_permute(rng_state);
+}
+
+// Uniform in [0, 1), from the top 24 bits so every value is exact
+fn rng_next_f32() -> f32 {
+    return unit_float(rng_next_u32());
+}
+
+// One PCG step as a hash (Jarzynski and Olano 2020)
+fn pcg_hash(x: u32) -> u32 {
+    return pcg_permute(x * 747796405u + 2891336453u);
+}
 
-    return bitcast<f32>(result) - 1.0;    // Range [0:1]
+fn pcg_permute(state: u32) -> u32 {
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
 }
 
 fn mat_4_to_3(m: mat4x4<f32>) -> mat3x3<f32> {
@@ -1,106 +1,328 @@
-use super::*;
-use wgpu_test::{gpu_test, FailureCase, GpuTestConfiguration, TestParameters};
-
-#[gpu_test]
-static COMPUTE_1: GpuTestConfiguration = GpuTestConfiguration::new()
-    .parameters(
-        TestParameters::default()
-            .downlevel_flags(wgpu::DownlevelFlags::COMPUTE_SHADERS)
-            .limits(wgpu::Limits::downlevel_defaults())
-            .skip(FailureCase::adapter("V3D")),
-    )
-    .run_async(|ctx| {
-        let input = &[1, 2, 3, 4];
-
-        async move { assert_execute_gpu(&ctx.device, &ctx.queue, input, &[0, 1, 7, 2]).await }
-    });
+// Tests across modules: the byte layout the shader reads, the CPU mirror of
+// its intersections, whole renders on a software adapter checked against
+// the CPU reference renderer, and the scenes in scenes/ against their
+// golden images.
 
-#[gpu_test]
-static COMPUTE_2: GpuTestConfiguration = GpuTestConfiguration::new()
-    .parameters(
-        TestParameters::default()
-            .downlevel_flags(wgpu::DownlevelFlags::COMPUTE_SHADERS)
-            .limits(wgpu::Limits::downlevel_defaults())
-            .skip(FailureCase::adapter("V3D")),
-    )
-    .run_async(|ctx| {
-        let input = &[5, 23, 10, 9];
-
-        async move { assert_execute_gpu(&ctx.device, &ctx.queue, input, &[5, 15, 6, 19]).await }
-    });
+use std::mem::{offset_of, size_of};
+use std::path::Path;
+use nalgebra::{Matrix4, Vector2, Vector3};
+use crate::camera::Camera;
+use crate::golden::{golden_settings, GoldenTest, UPDATE_VARIABLE};
+use crate::headless::{render_image, render_image_on};
+use crate::hitable::*;
+use crate::reference::ReferenceRenderer;
+use crate::renderer::Accumulation;
+use crate::{AdapterPreference, Integrator, RenderDevice, RenderSettings, Renderer, Scene, SceneCamera};
 
-#[gpu_test]
-static COMPUTE_OVERFLOW: GpuTestConfiguration = GpuTestConfiguration::new()
-    .parameters(
-        TestParameters::default()
-            .downlevel_flags(wgpu::DownlevelFlags::COMPUTE_SHADERS)
-            .limits(wgpu::Limits::downlevel_defaults())
-            .skip(FailureCase::adapter("V3D")),
-    )
-    .run_async(|ctx| {
-        let input = &[77031, 837799, 8400511, 63728127];
-        async move {
-            assert_execute_gpu(
-                &ctx.device,
-                &ctx.queue,
-                input,
-                &[350, 524, OVERFLOW, OVERFLOW],
-            )
-            .await
-        }
-    });
+// Offsets below follow WGSL's layout rules for the structs in shader.wgsl:
+// vec3 and mat4x4 align to 16 bytes, scalars to 4, structs to their largest member
+
+#[test]
+fn camera_matches_the_shader_layout() {
+    assert_eq!(offset_of!(Camera, aspect_ratio), 0);
+    assert_eq!(offset_of!(Camera, image_width), 4);
+    assert_eq!(offset_of!(Camera, image_height), 8);
+    assert_eq!(offset_of!(Camera, sampler_kind), 12);
+    assert_eq!(offset_of!(Camera, center), 16);
+    assert_eq!(offset_of!(Camera, pixel00_loc), 32);
+    assert_eq!(offset_of!(Camera, pixel_delta_u), 48);
+    assert_eq!(offset_of!(Camera, pixel_delta_v), 64);
+    // Scalars pack into the last slot of a vec3
+    assert_eq!(offset_of!(Camera, samples_per_pixel), 76);
+    assert_eq!(offset_of!(Camera, pixels_sample_scale), 80);
+    assert_eq!(offset_of!(Camera, max_depth), 84);
+    assert_eq!(offset_of!(Camera, iteration), 88);
+    assert_eq!(offset_of!(Camera, vfov), 92);
+    assert_eq!(offset_of!(Camera, rotation), 96);
+    assert_eq!(size_of::<Camera>(), 160);
+}
+
+#[test]
+fn hitable_matches_the_shader_layout() {
+    assert_eq!(size_of::<Sphere>(), 16);
+    assert_eq!(offset_of!(Sphere, radius), 12);
+    assert_eq!(offset_of!(Hitable, kind), 0);
+    assert_eq!(offset_of!(Hitable, instance), 4);
+    assert_eq!(offset_of!(Hitable, sphere), 16);
+    assert_eq!(offset_of!(Hitable, material), 32);
+    assert_eq!(size_of::<Hitable>(), 80);
+    // Arrays of hitables need no padding between elements
+    assert_eq!(size_of::<Hitable>() % 16, 0);
+}
+
+#[test]
+fn material_fields_land_where_the_shader_reads_them() {
+    // Material's fields are private, so look at its bytes instead
+    let mut list = HitableList::new();
+    list.add_texture(1, 1, &[[0; 4]]);
+    let texture = list.add_texture(3, 2, &[[255; 4]; 6]);
+    let material = Material::new(Vector3::new(0.1, 0.2, 0.3), METAL)
+        .with_texture(texture)
+        .with_ior(1.7)
+        .with_emission(Vector3::new(1.0, 2.0, 3.0), 2.0)
+        .with_roughness(0.25);
+    assert_eq!(size_of::<Material>(), 48);
+    let words: &[u32] = bytemuck::cast_slice(bytemuck::bytes_of(&material));
+    let float = |i: usize| f32::from_bits(words[i]);
+    assert_eq!([float(0), float(1), float(2)], [0.1, 0.2, 0.3]);
+    assert_eq!(words[3], METAL);
+    // Texture { offset, width, height }
+    assert_eq!(&words[4..7], &[1, 3, 2]);
+    assert_eq!(float(7), 1.7);
+    assert_eq!([float(8), float(9), float(10)], [2.0, 4.0, 6.0]);
+    assert_eq!(float(11), 0.25);
+}
+
+fn ray(origin: [f32; 3], direction: [f32; 3]) -> Ray {
+    Ray::new(Vector3::from(origin), Vector3::from(direction))
+}
+
+#[test]
+fn sphere_hits_take_the_nearest_root_in_range() {
+    let sphere = Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0);
+    assert_eq!(sphere.hit(&ray([0.0; 3], [0.0, 0.0, -1.0]), 0.001, 1e6), Some(4.0));
+    // Direction length scales t, as the shader never normalizes it
+    assert_eq!(sphere.hit(&ray([0.0; 3], [0.0, 0.0, -2.0]), 0.001, 1e6), Some(2.0));
+    // From inside only the far root is ahead
+    assert_eq!(sphere.hit(&ray([0.0, 0.0, -5.0], [0.0, 0.0, -1.0]), 0.001, 1e6), Some(1.0));
+    // The near root outside the range falls through to the far one
+    assert_eq!(sphere.hit(&ray([0.0; 3], [0.0, 0.0, -1.0]), 4.5, 1e6), Some(6.0));
+    assert_eq!(sphere.hit(&ray([0.0; 3], [0.0, 0.0, -1.0]), 0.001, 3.0), None);
+    // Behind the ray, and passing beside it
+    assert_eq!(sphere.hit(&ray([0.0; 3], [0.0, 0.0, 1.0]), 0.001, 1e6), None);
+    assert_eq!(sphere.hit(&ray([1.01, 0.0, 0.0], [0.0, 0.0, -1.0]), 0.001, 1e6), None);
+}
+
+#[test]
+fn sphere_records_face_the_ray() {
+    let mut list = HitableList::new();
+    list.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(0.0, 0.0, -5.0), 2.0), Material::dielectric(1.5)));
+
+    let outside = list.hit_record(0, &ray([0.0; 3], [0.0, 0.0, -1.0]), 0.001, 1e6).unwrap();
+    assert_eq!(outside.t, 3.0);
+    assert_eq!(outside.p, Vector3::new(0.0, 0.0, -3.0));
+    assert_eq!(outside.normal, Vector3::z());
+    assert!(outside.front_face);
+    assert_eq!((outside.shape, outside.size), (SPHERE, 2.0));
+
+    // Leaving the sphere the outward normal is flipped towards the ray
+    let inside = list.hit_record(0, &ray([0.0, 0.0, -5.0], [1.0, 0.0, 0.0]), 0.001, 1e6).unwrap();
+    assert_eq!(inside.p, Vector3::new(2.0, 0.0, -5.0));
+    assert_eq!(inside.normal, -Vector3::x());
+    assert!(!inside.front_face);
+}
+
+#[test]
+fn smooth_triangles_keep_their_face_normal() {
+    // Vertex normals leaning towards +x on a triangle facing +z
+    let leaning = Vector3::new(1.0, 0.0, 1.0).normalize();
+    let corners = vec![Vector3::zeros(), Vector3::x(), Vector3::y()];
+    let mesh = Mesh::new(corners, vec![[0, 1, 2]], Material::emissive(Vector3::new(1.0, 1.0, 1.0), 1.0))
+        .with_normals(vec![leaning; 3]);
+    let mut list = HitableList::new();
+    let id = list.add_mesh(mesh);
+    list.push_instance(id, Matrix4::new_translation(&Vector3::new(0.0, 0.0, -5.0)) * Matrix4::new_scaling(2.0), None);
+
+    let record = list.hit_record(0, &ray([0.5, 0.5, 0.0], [0.0, 0.0, -1.0]), 0.001, 1e6).unwrap();
+    assert_eq!(record.t, 5.0);
+    assert!((record.normal - leaning).norm() < 1e-6, "{}", record.normal);
+    // light_pdf needs the triangle's own normal and world space area
+    assert!((record.face_normal - Vector3::z()).norm() < 1e-6, "{}", record.face_normal);
+    assert_eq!((record.shape, record.size), (MESH, 2.0));
+}
+
+#[test]
+fn textures_are_filtered_in_linear_space() {
+    let mut list = HitableList::new();
+    let texture = list.add_texture(2, 1, &[[255, 0, 0, 255], [0, 0, 255, 255]]);
+    let material = Material::new(Vector3::new(1.0, 1.0, 0.5), LAMBERTIAN).with_texture(texture);
+    let at = |u: f32| list.apply_texture(material, Vector2::new(u, 0.5)).albedo();
+    // Texel centers take one texel, halfway between them is the linear average
+    assert!((at(0.25) - Vector3::new(1.0, 0.0, 0.0)).norm() < 1e-6);
+    assert!((at(0.75) - Vector3::new(0.0, 0.0, 0.5)).norm() < 1e-6);
+    assert!((at(0.5) - Vector3::new(0.5, 0.0, 0.25)).norm() < 1e-6);
+    // and the edges wrap around
+    assert!((at(0.0) - at(0.5)).norm() < 1e-6);
+}
+
+// The software adapter, or None where the machine has none
+fn software_adapter() -> Option<wgpu::Adapter> {
+    let instance = wgpu::Instance::default();
+    let adapter = pollster::block_on(AdapterPreference::Fallback.try_request_adapter(&instance, None));
+    if adapter.is_none() {
+        eprintln!("no software adapter, skipping the GPU render");
+    }
+    adapter
+}
+
+fn small_scene() -> Scene {
+    let mut list = HitableList::new();
+    let floor = Mesh::new(
+        vec![
+            Vector3::new(-3.0, -0.5, 1.0),
+            Vector3::new(3.0, -0.5, 1.0),
+            Vector3::new(3.0, -0.5, -5.0),
+            Vector3::new(-3.0, -0.5, -5.0),
+        ],
+        vec![[0, 1, 2], [0, 2, 3]],
+        Material::new(Vector3::new(0.7, 0.7, 0.7), LAMBERTIAN),
+    );
+    list.push_mesh(floor);
+    list.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(-0.6, 0.0, -2.0), 0.5), Material::new(Vector3::new(0.8, 0.2, 0.2), LAMBERTIAN)));
+    list.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(0.6, 0.0, -2.0), 0.5), Material::dielectric(1.5)));
+    list.push(Hitable::new(SPHERE, Sphere::new(Vector3::new(0.0, 1.2, -2.5), 0.3), Material::emissive(Vector3::new(1.0, 0.9, 0.7), 4.0)));
+    Scene::new(list)
+        .with_camera(SceneCamera {
+            center: Vector3::new(0.0, 0.3, 0.0),
+            ..Default::default()
+        })
+        .with_settings(RenderSettings {
+            width: 32,
+            height: 24,
+            samples_per_pixel: 2,
+            max_depth: 6,
+            iterations: 2,
+            ..Default::default()
+        })
+}
 
-#[cfg(not(target_arch = "wasm32"))]
-#[gpu_test]
-static MULTITHREADED_COMPUTE: GpuTestConfiguration = GpuTestConfiguration::new()
-    .parameters(
-        TestParameters::default()
-            .downlevel_flags(wgpu::DownlevelFlags::COMPUTE_SHADERS)
-            .limits(wgpu::Limits::downlevel_defaults())
-            .skip(FailureCase::adapter("V3D")),
-    )
-    .run_sync(|ctx| {
-        use std::{sync::mpsc, sync::Arc, thread, time::Duration};
-
-        let ctx = Arc::new(ctx);
-
-        let thread_count = 8;
-
-        let (tx, rx) = mpsc::channel();
-        let workers: Vec<_> = (0..thread_count)
-            .map(move |_| {
-                let tx = tx.clone();
-                let ctx = Arc::clone(&ctx);
-                thread::spawn(move || {
-                    let input = &[100, 100, 100];
-                    pollster::block_on(assert_execute_gpu(
-                        &ctx.device,
-                        &ctx.queue,
-                        input,
-                        &[25, 25, 25],
-                    ));
-                    tx.send(true).unwrap();
-                })
-            })
-            .collect();
-
-        for _ in 0..thread_count {
-            rx.recv_timeout(Duration::from_secs(10))
-                .expect("A thread never completed.");
+// Both trace the same paths with the same numbers, so they only differ by
+// floating point: a step of the 8 bit output here and there
+fn assert_matches_reference(image: &image::RgbaImage, scene: Scene) {
+    let reference = ReferenceRenderer::new(scene).render_image();
+    assert_eq!(image.dimensions(), reference.dimensions());
+    let differences: Vec<u8> = image
+        .pixels()
+        .zip(reference.pixels())
+        .flat_map(|(a, b)| (0..3).map(move |c| a.0[c].abs_diff(b.0[c])))
+        .collect();
+    let close = differences.iter().filter(|&&d| d <= 2).count();
+    assert!(close * 100 >= differences.len() * 99, "{} of {} channels differ by more than 2", differences.len() - close, differences.len());
+    let mean = differences.iter().map(|&d| d as f32).sum::<f32>() / differences.len() as f32;
+    assert!(mean < 0.5, "mean difference {}", mean);
+}
+
+#[test]
+fn software_renders_match_the_reference() {
+    let Some(adapter) = software_adapter() else {
+        return;
+    };
+    for integrator in [Integrator::Compute, Integrator::Downlevel] {
+        if integrator == Integrator::Compute && Integrator::supported(&adapter) != Integrator::Compute {
+            continue;
         }
+        let image = pollster::block_on(render_image_on(small_scene(), &adapter, Some(integrator)));
+        assert_matches_reference(&image, small_scene());
+    }
+}
+
+// With or without a software adapter there's an image, from the GPU or the CPU
+#[test]
+fn render_image_falls_back_to_the_cpu() {
+    let image = pollster::block_on(render_image(small_scene(), AdapterPreference::Fallback, None));
+    assert_matches_reference(&image, small_scene());
+}
+
+#[test]
+fn renderers_use_the_integrator_their_device_was_wrapped_with() {
+    let Some(adapter) = software_adapter() else {
+        return;
+    };
+    // The adapter's own limits would allow compute, the integrator still wins
+    let (device, queue) = pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            required_limits: adapter.limits(),
+            ..Default::default()
+        },
+        None,
+    ))
+    .unwrap();
+    let device = RenderDevice::new(&adapter, device, queue, Some(Integrator::Downlevel));
+    let scene = small_scene();
+    let (width, height) = (scene.settings.width, scene.settings.height);
+    let mut renderer = Renderer::for_texture(device, scene, width, height);
+    assert_eq!(renderer.integrator(), Integrator::Downlevel);
+    while !renderer.is_converged() {
+        renderer.step().unwrap();
+    }
+    assert_matches_reference(&renderer.read_image().unwrap(), small_scene());
+}
 
-        for worker in workers {
-            worker.join().unwrap();
+#[test]
+fn resizing_starts_a_new_history() {
+    let Some(adapter) = software_adapter() else {
+        return;
+    };
+    let device = pollster::block_on(Renderer::request_device(&adapter, None));
+    let mut renderer = Renderer::for_texture(device, small_scene(), 32, 24);
+    while !renderer.is_converged() {
+        renderer.step().unwrap();
+    }
+    for (width, height) in [(40, 30), (16, 12)] {
+        renderer.resize(width, height);
+        assert_eq!(renderer.accumulation(), Accumulation::Reset);
+        assert_eq!(renderer.history_size(), (width, height));
+        while !renderer.is_converged() {
+            renderer.step().unwrap();
         }
+        let image = renderer.read_image().unwrap();
+        assert_eq!(image.dimensions(), (width, height));
+        // Only this size's iterations are in the average
+        assert_eq!(renderer.iteration(), small_scene().settings.iterations);
+        let scene = small_scene();
+        let settings = RenderSettings { width, height, ..scene.settings };
+        assert_matches_reference(&image, scene.with_settings(settings));
+    }
+}
+
+#[test]
+fn empty_scene_renders_the_sky() {
+    let Some(adapter) = software_adapter() else {
+        return;
+    };
+    let scene = Scene::new(HitableList::new()).with_settings(RenderSettings {
+        width: 8,
+        height: 16,
+        samples_per_pixel: 1,
+        iterations: 1,
+        ..Default::default()
     });
+    let image = pollster::block_on(render_image_on(scene.clone(), &adapter, None));
+    assert_matches_reference(&image, scene);
+    // White at the horizon, bluer looking up
+    let (top, bottom) = (image.get_pixel(4, 0), image.get_pixel(4, 15));
+    assert!(top.0[0] < bottom.0[0] && top.0[2] == 255, "top {:?} bottom {:?}", top, bottom);
+}
 
-async fn assert_execute_gpu(
-    device: &wgpu::Device,
-    queue: &wgpu::Queue,
-    input: &[u32],
-    expected: &[u32],
-) {
-    if let Some(produced) = execute_gpu_inner(device, queue, input).await {
-        assert_eq!(produced, expected);
+// Every scene file is rendered and compared with scenes/golden/<name>.png,
+// mismatches end up in target/golden. Set WGSL_UPDATE_GOLDEN after an
+// intended change to write new references.
+#[test]
+fn scenes_match_their_golden_images() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let golden = GoldenTest::new(root.join("scenes/golden"), root.join("target/golden"));
+    // The CPU reference traces the same paths where there's no adapter
+    let adapter = software_adapter();
+    let mut paths: Vec<_> = std::fs::read_dir(root.join("scenes"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "toml"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    let mut failures = Vec::new();
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let mut scene = Scene::load(&path).unwrap_or_else(|err| panic!("Failed to load {}: {}", path.display(), err));
+        scene.settings = golden_settings(scene.settings);
+        let image = match &adapter {
+            Some(adapter) => pollster::block_on(render_image_on(scene, adapter, None)),
+            None => ReferenceRenderer::new(scene).render_image(),
+        };
+        if let Err(failure) = golden.check(&name, &image) {
+            failures.push(failure);
+        }
     }
+    assert!(failures.is_empty(), "{}\nafter intended changes rerun with {} set", failures.join("\n"), UPDATE_VARIABLE);
 }